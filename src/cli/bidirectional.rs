@@ -0,0 +1,245 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use differ_backup::{
+    diffing::{build_three_way_diff, DiffOptions, SafetyChecks},
+    drivers::{SizeMode, Snapshot},
+    events::Events,
+    sync::{apply_sync_plan, plan_sync, ConflictPolicy, Replica, SyncAction, SyncSide},
+};
+
+use super::{
+    format::{describe_change, human_size},
+    progress::Progress,
+};
+use crate::{info, success, warn};
+
+/// Synchronize two directories with each other, relative to the baseline saved by the previous run
+#[allow(clippy::too_many_arguments)]
+pub fn sync_bidirectional(
+    a: Replica,
+    a_snapshot: Snapshot,
+    b: Replica,
+    b_snapshot: Snapshot,
+    baseline_path: &Path,
+    policy: ConflictPolicy,
+    dry_run: bool,
+    diff_options: &DiffOptions,
+    safety: &SafetyChecks,
+    progress: &Progress,
+    events: &Events,
+) -> Result<()> {
+    let supports_transfers = a.driver.supports_transfers() && b.driver.supports_transfers();
+
+    if !dry_run && !supports_transfers {
+        bail!("Both drivers must be able to transfer files for a bidirectional synchronization");
+    }
+
+    let baseline = if baseline_path.exists() {
+        Snapshot::load(baseline_path)?
+    } else {
+        info!("No baseline found, items which differ on both sides will be reported as conflicts.");
+
+        Snapshot {
+            path: a.root.to_string(),
+            items: vec![],
+        }
+    };
+
+    safety.check_replicas(&a_snapshot, &b_snapshot, &baseline)?;
+
+    let (a_items, b_items) = (a_snapshot.items.len(), b_snapshot.items.len());
+
+    // Only contents are synchronized, metadata changes would be reported as conflicts
+    let diff_options = DiffOptions {
+        compare_permissions: false,
+        compare_ownership: false,
+        compare_xattrs: false,
+        ..*diff_options
+    };
+
+    info!("Comparing both directories with the baseline...");
+
+    let diff = build_three_way_diff(
+        &baseline,
+        a_snapshot.clone(),
+        b_snapshot.clone(),
+        &diff_options,
+    );
+
+    let plan = plan_sync(&diff, &a_snapshot, &b_snapshot, policy);
+
+    let name = |side: SyncSide| match side {
+        SyncSide::A => a.root,
+        SyncSide::B => b.root,
+    };
+
+    for side in [SyncSide::A, SyncSide::B] {
+        let actions = plan
+            .actions
+            .iter()
+            .filter(|action| match action {
+                SyncAction::CreateDir { side: s, .. }
+                | SyncAction::CopyFile { side: s, .. }
+                | SyncAction::Remove { side: s, .. } => *s == side,
+                SyncAction::KeepBoth { .. } => false,
+            })
+            .collect::<Vec<_>>();
+
+        if actions.is_empty() {
+            continue;
+        }
+
+        info!("Changes to apply to {}:", name(side));
+
+        for action in actions {
+            match action {
+                SyncAction::CreateDir { item, .. } => {
+                    println!(" {}", format!("+ {}/", item.path).bright_green())
+                }
+                SyncAction::CopyFile { item, .. } => println!(
+                    " {} {}",
+                    format!("+ {}", item.path).bright_green(),
+                    format!(
+                        "({})",
+                        human_size(item.metadata.size_with(SizeMode::Apparent).unwrap_or(0))
+                    )
+                    .bright_yellow()
+                ),
+                SyncAction::Remove { path, is_dir, .. } => println!(
+                    " {}",
+                    format!("- {}{}", path, if *is_dir { "/" } else { "" }).bright_red()
+                ),
+                SyncAction::KeepBoth { .. } => unreachable!(),
+            }
+        }
+
+        println!();
+    }
+
+    let kept_both = plan
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            SyncAction::KeepBoth {
+                newer_side,
+                newer,
+                conflict_path,
+                ..
+            } => Some((newer_side, newer, conflict_path)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if !kept_both.is_empty() {
+        info!("Conflicts resolved by keeping both versions:");
+
+        for (newer_side, newer, conflict_path) in kept_both {
+            println!(
+                " {} {}",
+                newer.path.to_string().bright_yellow(),
+                format!(
+                    "(newer in {}, older kept as {})",
+                    name(*newer_side),
+                    conflict_path
+                )
+                .bright_yellow()
+            );
+        }
+
+        println!();
+    }
+
+    if !plan.conflicts.is_empty() {
+        warn!("Conflicts to resolve manually:");
+
+        for item in &plan.conflicts {
+            warn!(
+                " {} ({}: {}, {}: {})",
+                item.path,
+                a.root,
+                describe_change(item.status.a()),
+                b.root,
+                describe_change(item.status.b())
+            );
+        }
+
+        warn!("");
+    }
+
+    if !plan.blocked.is_empty() {
+        warn!(
+            "{} changes inside conflicting directories will only be synchronized once the conflicts are resolved.",
+            plan.blocked.len()
+        );
+    }
+
+    for (path, reason) in &plan.skipped {
+        warn!("Warning: skipped {} ({})", path, reason);
+    }
+
+    let deletions = |side: SyncSide| {
+        plan.actions
+            .iter()
+            .filter(|action| matches!(action, SyncAction::Remove { side: s, .. } if *s == side))
+            .count()
+    };
+
+    safety.check_deletions(deletions(SyncSide::A), a_items)?;
+    safety.check_deletions(deletions(SyncSide::B), b_items)?;
+
+    safety.check_sync_deletions(a.root, deletions(SyncSide::A), baseline.items.len())?;
+    safety.check_sync_deletions(b.root, deletions(SyncSide::B), baseline.items.len())?;
+
+    if dry_run {
+        success!(
+            "Dry run: {} actions would be applied, {} conflicts would be left.",
+            plan.actions.len(),
+            plan.conflicts.len()
+        );
+        return Ok(());
+    }
+
+    if !plan.actions.is_empty() {
+        let (files, bytes) = plan
+            .actions
+            .iter()
+            .map(SyncAction::transfer_size)
+            .fold((0, 0), |(files, bytes), (f, b)| (files + f, bytes + b));
+
+        progress.set_planned(files, bytes);
+
+        info!(
+            "Synchronizing {} items ({} to transfer)...",
+            plan.actions.len().to_string().bright_yellow(),
+            human_size(bytes).bright_yellow()
+        );
+    }
+
+    let outcome = apply_sync_plan(&plan, a, b, &baseline, events);
+
+    progress.finish();
+
+    outcome.baseline.save(baseline_path)?;
+
+    for (path, err) in &outcome.errors {
+        warn!("Failed to synchronize {}: {:?}", path, err);
+    }
+
+    if outcome.errors.is_empty() && plan.conflicts.is_empty() {
+        success!(
+            "Both directories are in sync ({} actions applied).",
+            outcome.applied
+        );
+    } else {
+        bail!(
+            "Synchronization incomplete: {} actions applied, {} failed, {} conflicts left",
+            outcome.applied,
+            outcome.errors.len(),
+            plan.conflicts.len()
+        );
+    }
+
+    Ok(())
+}
@@ -1,18 +1,490 @@
-use clap::Parser;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use clap::{Parser, Subcommand};
+use differ_backup::units::{parse_age, parse_size};
+
+use differ_backup::{
+    diffing::{CompareMode, UnicodeNormalization},
+    drivers::{BandwidthSchedule, SizeMode},
+    sync::ConflictPolicy,
+};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Args {
+    /// Other commands
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Source directory
-    #[clap(help = "Source directory")]
-    pub source_dir: String,
+    #[clap(required_unless_present = "mappings", help = "Source directory")]
+    pub source_dir: Option<String>,
 
-    /// Destination directory
-    #[clap(help = "Destination directory (to synchronize with the source directory)")]
-    pub dest_dir: String,
+    /// Destination directories
+    #[clap(
+        required_unless_present = "mappings",
+        help = "Destination directories (to synchronize with the source directory), the source is only scanned once when several are provided"
+    )]
+    pub dest_dirs: Vec<String>,
+
+    /// Mapped source directories
+    #[clap(
+        long = "map",
+        conflicts_with = "bidirectional",
+        help = "Source directory to place in a subdirectory of the destination, as 'SOURCE=SUBDIR' (can be repeated, all positional arguments are then destinations)"
+    )]
+    pub mappings: Vec<SourceMapping>,
 
     /// Names to ignore
     #[clap(short = 'i', long = "ignore", help = "Names to ignore when diffing")]
     pub ignore: Vec<String>,
+
+    /// Maximum number of deletions
+    #[clap(
+        long = "max-delete",
+        help = "Abort if more than this number of items would be deleted from the destination"
+    )]
+    pub max_delete: Option<usize>,
+
+    /// Maximum percentage of deletions
+    #[clap(
+        long = "max-delete-percent",
+        help = "Abort if more than this percentage of the destination's items would be deleted"
+    )]
+    pub max_delete_percent: Option<f64>,
+
+    /// Allow an empty source
+    #[clap(
+        long = "allow-empty-source",
+        help = "Don't abort when the source directory is empty but the destination is not (in bidirectional mode, when either directory is empty or almost emptied since the baseline)"
+    )]
+    pub allow_empty_source: bool,
+
+    /// Source marker file
+    #[clap(
+        long = "source-marker",
+        help = "Abort if this file doesn't exist at the root of the source directory"
+    )]
+    pub source_marker: Option<String>,
+
+    /// Destination marker file
+    #[clap(
+        long = "dest-marker",
+        help = "Abort if this file doesn't exist at the root of the destination directory"
+    )]
+    pub dest_marker: Option<String>,
+
+    /// Comparison mode
+    #[clap(
+        long = "compare",
+        default_value = "mtime-size",
+        help = "How to compare files: 'mtime-size', 'size-only' or 'checksum' (ignore modification dates if contents are identical)"
+    )]
+    pub compare: CompareMode,
+
+    /// Modification time tolerance
+    #[clap(
+        long = "mtime-tolerance",
+        default_value = "0",
+        help = "Maximum difference in seconds between modification times for them to be considered equal (e.g. 2 for FAT disks)"
+    )]
+    pub mtime_tolerance: f64,
+
+    /// Modification time offset
+    #[clap(
+        long = "mtime-offset",
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Offset in seconds added to the destination's modification times before comparing them (e.g. 3600 for a FAT disk after a DST change)"
+    )]
+    pub mtime_offset: i64,
+
+    /// Compare permissions
+    #[clap(long = "permissions", help = "Compare permission bits (mode)")]
+    pub permissions: bool,
+
+    /// Compare ownership
+    #[clap(long = "ownership", help = "Compare owner and group IDs")]
+    pub ownership: bool,
+
+    /// Compare extended attributes
+    #[clap(
+        long = "xattrs",
+        help = "Read and compare extended attributes, including POSIX ACLs (local filesystem only)"
+    )]
+    pub xattrs: bool,
+
+    /// Apply metadata changes
+    #[clap(
+        long = "apply-metadata",
+        help = "Apply permission, ownership and extended attribute changes to the destination (contents are not transferred)"
+    )]
+    pub apply_metadata: bool,
+
+    /// Detect hard links
+    #[clap(
+        long = "hard-links",
+        help = "Detect hard links in the source and report them as links instead of separate files"
+    )]
+    pub hard_links: bool,
+
+    /// Apply hard links
+    #[clap(
+        long = "apply-hard-links",
+        help = "Recreate hard links on the destination when their target is already up to date"
+    )]
+    pub apply_hard_links: bool,
+
+    /// Size mode
+    #[clap(
+        long = "size-mode",
+        default_value = "apparent",
+        help = "Which size to report: 'apparent' (file length) or 'allocated' (actual disk usage)"
+    )]
+    pub size_mode: SizeMode,
+
+    /// Skip special files
+    #[clap(
+        long = "skip-special",
+        help = "Ignore named pipes, sockets and device nodes on both sides"
+    )]
+    pub skip_special: bool,
+
+    /// Apply special files
+    #[clap(
+        long = "apply-special",
+        help = "Create added named pipes and device nodes on the destination (where permitted)"
+    )]
+    pub apply_special: bool,
+
+    /// Unicode normalization
+    #[clap(
+        long = "normalize",
+        help = "Normalize paths to 'nfc' or 'nfd' before comparing them (e.g. for macOS destinations)"
+    )]
+    pub normalize: Option<UnicodeNormalization>,
+
+    /// Case-insensitive comparison
+    #[clap(
+        long = "case-insensitive",
+        help = "Compare paths case-insensitively (e.g. for FAT, exFAT or default macOS destinations)"
+    )]
+    pub case_insensitive: bool,
+
+    /// Stay on one filesystem
+    #[clap(
+        short = 'x',
+        long = "one-file-system",
+        help = "Don't descend into directories on other filesystems (local filesystem only)"
+    )]
+    pub one_file_system: bool,
+
+    /// Mount points to include
+    #[clap(
+        long = "include-mount",
+        help = "Mount point to descend into anyway when using --one-file-system"
+    )]
+    pub include_mount: Vec<String>,
+
+    /// Mount points to exclude
+    #[clap(
+        long = "exclude-mount",
+        help = "Mount point (or any directory) of the source to skip entirely"
+    )]
+    pub exclude_mount: Vec<String>,
+
+    /// Maximum depth
+    #[clap(
+        long = "max-depth",
+        help = "Only walk this many levels below the root directories (1 being their direct children)"
+    )]
+    pub max_depth: Option<usize>,
+
+    /// Minimum file size
+    #[clap(
+        long = "min-size",
+        parse(try_from_str = parse_size),
+        help = "Ignore files smaller than this size (e.g. '10k', '500M', '4GiB')"
+    )]
+    pub min_size: Option<u64>,
+
+    /// Maximum file size
+    #[clap(
+        long = "max-size",
+        parse(try_from_str = parse_size),
+        help = "Ignore files larger than this size (e.g. '10k', '500M', '4GiB')"
+    )]
+    pub max_size: Option<u64>,
+
+    /// Newer than
+    #[clap(
+        long = "newer-than",
+        parse(try_from_str = parse_age),
+        help = "Ignore files last modified longer ago than this (e.g. '30d', '12h', '2w')"
+    )]
+    pub newer_than: Option<u64>,
+
+    /// Older than
+    #[clap(
+        long = "older-than",
+        parse(try_from_str = parse_age),
+        help = "Ignore files last modified more recently than this (e.g. '30d', '12h', '2w')"
+    )]
+    pub older_than: Option<u64>,
+
+    /// Summarize changes by directory
+    #[clap(
+        long = "summary-depth",
+        help = "Instead of listing every item, summarize changes by directory up to this depth (0 for the root only)"
+    )]
+    pub summary_depth: Option<usize>,
+
+    /// Render changes as a tree
+    #[clap(
+        long = "tree",
+        conflicts_with = "summary-depth",
+        help = "Render changes as a tree, collapsing directories which are entirely added or deleted"
+    )]
+    pub tree: bool,
+
+    /// HTML report
+    #[clap(
+        long = "html-report",
+        help = "Write a self-contained HTML report of the differences to this file"
+    )]
+    pub html_report: Option<String>,
+
+    /// Output format
+    #[clap(
+        long = "format",
+        default_value = "text",
+        help = "Output format: 'text' (report for humans) or 'csv' (one row per item, nothing is applied)"
+    )]
+    pub format: OutputFormat,
+
+    /// Output file
+    #[clap(
+        long = "output",
+        help = "Write the CSV output to this file instead of the standard output"
+    )]
+    pub output: Option<String>,
+
+    /// Bandwidth limit
+    #[clap(
+        long = "bwlimit",
+        help = "Limit the throughput of file contents written through the destination driver, in bytes per second, optionally by time of day (e.g. '2M' or '08:00-18:00@1M,10M')"
+    )]
+    pub bwlimit: Option<BandwidthSchedule>,
+
+    /// Source read limit
+    #[clap(
+        long = "read-limit",
+        help = "Limit the throughput of file contents read from a local source, same format as --bwlimit"
+    )]
+    pub read_limit: Option<BandwidthSchedule>,
+
+    /// Source scan threads
+    #[clap(
+        long = "source-scan-threads",
+        parse(try_from_str = parse_thread_count),
+        help = "Number of threads for scanning the source directory (defaults to the number of CPUs)"
+    )]
+    pub source_scan_threads: Option<usize>,
+
+    /// Source transfer threads
+    #[clap(
+        long = "source-transfer-threads",
+        parse(try_from_str = parse_thread_count),
+        help = "Number of threads for reading or writing file contents on the source side (defaults to the number of CPUs)"
+    )]
+    pub source_transfer_threads: Option<usize>,
+
+    /// Destination scan threads
+    #[clap(
+        long = "dest-scan-threads",
+        parse(try_from_str = parse_thread_count),
+        help = "Number of threads for scanning the destination directory (defaults to the number of CPUs)"
+    )]
+    pub dest_scan_threads: Option<usize>,
+
+    /// Destination transfer threads
+    #[clap(
+        long = "dest-transfer-threads",
+        parse(try_from_str = parse_thread_count),
+        help = "Number of threads for reading or writing file contents on the destination side (defaults to the number of CPUs)"
+    )]
+    pub dest_transfer_threads: Option<usize>,
+
+    /// Bidirectional synchronization
+    #[clap(
+        long = "bidirectional",
+        requires = "baseline",
+        help = "Synchronize both directories with each other, propagating the changes made on each side since the last synchronization"
+    )]
+    pub bidirectional: bool,
+
+    /// Baseline snapshot
+    #[clap(
+        long = "baseline",
+        requires = "bidirectional",
+        help = "File storing the state of both directories after the last bidirectional synchronization (created if it doesn't exist)"
+    )]
+    pub baseline: Option<String>,
+
+    /// Conflict policy
+    #[clap(
+        long = "conflict-policy",
+        default_value = "manual",
+        help = "How to resolve files changed on both sides: 'manual' (report them), 'newer' (keep the most recent) or 'keep-both' (keep the older one with a '.conflict' suffix)"
+    )]
+    pub conflict_policy: ConflictPolicy,
+
+    /// Dry run
+    #[clap(
+        long = "dry-run",
+        requires = "bidirectional",
+        help = "Only report what the bidirectional synchronization would do"
+    )]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compare two replicas to a baseline snapshot, classifying the changes made to each of them
+    Diff3(Diff3Args),
+
+    /// Save the snapshot of a directory to a file, e.g. to use it as a baseline
+    Snapshot(SnapshotArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Diff3Args {
+    /// Baseline snapshot
+    #[clap(
+        help = "Snapshot file both replicas are compared to (saved with the 'snapshot' command or by a bidirectional synchronization)"
+    )]
+    pub baseline: String,
+
+    /// First replica
+    #[clap(help = "First replica (directory, or SFTP location)")]
+    pub a: String,
+
+    /// Second replica
+    #[clap(help = "Second replica (directory, or SFTP location)")]
+    pub b: String,
+
+    /// Names to ignore
+    #[clap(short = 'i', long = "ignore", help = "Names to ignore when diffing")]
+    pub ignore: Vec<String>,
+
+    /// Modification time tolerance
+    #[clap(
+        long = "mtime-tolerance",
+        default_value = "0",
+        help = "Maximum difference in seconds between modification times for them to be considered equal (e.g. 2 for FAT disks)"
+    )]
+    pub mtime_tolerance: f64,
+
+    /// Compare permissions
+    #[clap(long = "permissions", help = "Compare permission bits (mode)")]
+    pub permissions: bool,
+
+    /// Compare ownership
+    #[clap(long = "ownership", help = "Compare owner and group IDs")]
+    pub ownership: bool,
+
+    /// Skip special files
+    #[clap(
+        long = "skip-special",
+        help = "Ignore named pipes, sockets and device nodes everywhere"
+    )]
+    pub skip_special: bool,
+
+    /// Output format
+    #[clap(
+        long = "format",
+        default_value = "text",
+        help = "Output format: 'text' (report for humans) or 'csv' (one row per path)"
+    )]
+    pub format: OutputFormat,
+
+    /// Output file
+    #[clap(
+        long = "output",
+        help = "Write the CSV output to this file instead of the standard output"
+    )]
+    pub output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SnapshotArgs {
+    /// Directory
+    #[clap(help = "Directory to snapshot (or SFTP location)")]
+    pub dir: String,
+
+    /// Snapshot file
+    #[clap(help = "File to save the snapshot to")]
+    pub output: String,
+
+    /// Names to ignore
+    #[clap(
+        short = 'i',
+        long = "ignore",
+        help = "Names to ignore when walking the directory"
+    )]
+    pub ignore: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceMapping {
+    pub source: String,
+    pub subdir: String,
+}
+
+impl FromStr for SourceMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((source, subdir)) if !source.is_empty() && !subdir.is_empty() => Ok(Self {
+                source: source.to_string(),
+                subdir: subdir.trim_end_matches('/').to_string(),
+            }),
+            _ => bail!("Invalid mapping '{}' (expected 'SOURCE=SUBDIR')", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            _ => bail!("Unknown output format '{}' (expected 'text' or 'csv')", s),
+        }
+    }
+}
+
+fn parse_thread_count(s: &str) -> Result<usize> {
+    match s.parse()? {
+        0 => bail!("Number of threads must be at least 1"),
+        threads => Ok(threads),
+    }
 }
@@ -0,0 +1,142 @@
+use std::io::{self, Write};
+
+use differ_backup::{
+    diffing::{DiffItem, DiffType, ThreeWayItem},
+    drivers::{DriverItemMetadata, SizeMode},
+};
+
+use super::format::{format_iso8601, type_name};
+
+const HEADER: &[&str] = &[
+    "category",
+    "path",
+    "type",
+    "prev_size",
+    "new_size",
+    "prev_mtime",
+    "new_mtime",
+    "size_delta",
+];
+
+/// Report with one CSV row per item, each row being written as soon as it is added
+pub struct CsvReport<W: Write> {
+    writer: W,
+    size_mode: SizeMode,
+}
+
+impl<W: Write> CsvReport<W> {
+    /// Start a report by writing its header
+    pub fn new(mut writer: W, size_mode: SizeMode) -> io::Result<Self> {
+        writeln!(writer, "{}", HEADER.join(","))?;
+
+        Ok(Self { writer, size_mode })
+    }
+
+    pub fn write_item(&mut self, item: &DiffItem) -> io::Result<()> {
+        let prev = item.status.prev_metadata();
+        let new = item.status.new_metadata();
+
+        // Type of the item after the changes are applied, or before its deletion
+        let item_type = new.or(prev).map(type_name).unwrap_or_default();
+
+        let size = |m: Option<DriverItemMetadata>| m.and_then(|m| m.size_with(self.size_mode));
+
+        let mtime = |m: Option<DriverItemMetadata>| match m {
+            Some(DriverItemMetadata::File(m)) => {
+                format_iso8601(m.modification_date, m.modification_date_nanos)
+            }
+            _ => String::new(),
+        };
+
+        let (prev_size, new_size) = (size(prev), size(new));
+
+        let size_delta = match (prev_size, new_size) {
+            (None, None) => String::new(),
+            (prev_size, new_size) => {
+                (i128::from(new_size.unwrap_or(0)) - i128::from(prev_size.unwrap_or(0))).to_string()
+            }
+        };
+
+        let fields = [
+            item.status.name().to_string(),
+            item.path.to_string(),
+            item_type.to_string(),
+            prev_size.map(|s| s.to_string()).unwrap_or_default(),
+            new_size.map(|s| s.to_string()).unwrap_or_default(),
+            mtime(prev),
+            mtime(new),
+            size_delta,
+        ];
+
+        let fields = fields.iter().map(|field| escape(field)).collect::<Vec<_>>();
+
+        writeln!(self.writer, "{}", fields.join(","))
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write one CSV row per item as they are consumed
+pub fn write_csv_report(
+    items: impl Iterator<Item = DiffItem>,
+    writer: impl Write,
+    size_mode: SizeMode,
+) -> io::Result<()> {
+    let mut report = CsvReport::new(writer, size_mode)?;
+
+    for item in items {
+        report.write_item(&item)?;
+    }
+
+    report.finish()
+}
+
+const THREE_WAY_HEADER: &[&str] = &["status", "path", "a_change", "b_change", "a_type", "b_type"];
+
+/// Write one CSV row per path of a three-way comparison
+pub fn write_three_way_csv_report(
+    items: impl Iterator<Item = ThreeWayItem>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writeln!(writer, "{}", THREE_WAY_HEADER.join(","))?;
+
+    for item in items {
+        let change = |change: Option<&DiffType>| change.map(DiffType::name).unwrap_or_default();
+
+        // Type of the item after the change, if it still exists
+        let item_type = |change: Option<&DiffType>| {
+            change
+                .and_then(DiffType::new_metadata)
+                .map(type_name)
+                .unwrap_or_default()
+        };
+
+        let (a, b) = (item.status.a(), item.status.b());
+
+        let fields = [
+            item.status.name().to_string(),
+            item.path.to_string(),
+            change(a).to_string(),
+            change(b).to_string(),
+            item_type(a).to_string(),
+            item_type(b).to_string(),
+        ];
+
+        let fields = fields.iter().map(|field| escape(field)).collect::<Vec<_>>();
+
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+
+    writer.flush()
+}
+
+/// Quote a field if it contains a separator, a quote or a line break
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    fs,
+    io::{stdout, BufWriter},
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use differ_backup::{
+    diffing::{build_three_way_diff, DiffOptions, DiffType, ThreeWayItem, ThreeWayStatus},
+    drivers::{fs::FsDriver, make_snapshot, DriverPools, Snapshot, WalkFilters},
+    events::{Events, Side},
+};
+
+use super::{
+    cmd::{Diff3Args, OutputFormat},
+    csv::write_three_way_csv_report,
+    format::describe_change,
+    logging::reserve_stdout,
+    program::{driver_from_arg, event_printer, join_thread},
+    progress::Progress,
+};
+use crate::{info, success, warn};
+
+pub fn diff3_main(args: Diff3Args) -> Result<()> {
+    if args.format == OutputFormat::Csv {
+        if args.output.is_none() {
+            reserve_stdout();
+        }
+    } else if args.output.is_some() {
+        bail!("An output file can only be provided for the CSV output format");
+    }
+
+    if !args.mtime_tolerance.is_finite() || args.mtime_tolerance < 0.0 {
+        bail!("Modification time tolerance must be a positive number of seconds");
+    }
+
+    let baseline = Snapshot::load(Path::new(&args.baseline))?;
+
+    let (a_driver, a_dir) =
+        driver_from_arg(&args.a, FsDriver::new(), None, None, DriverPools::default())?;
+    let (b_driver, b_dir) =
+        driver_from_arg(&args.b, FsDriver::new(), None, None, DriverPools::default())?;
+
+    let filters = WalkFilters {
+        ignore: args.ignore.iter().cloned().collect(),
+        ..Default::default()
+    };
+
+    let diff_options = DiffOptions {
+        mtime_tolerance: Duration::from_secs_f64(args.mtime_tolerance),
+        compare_permissions: args.permissions,
+        compare_ownership: args.ownership,
+        skip_special: args.skip_special,
+        ..Default::default()
+    };
+
+    info!("Building snapshots of both replicas...");
+
+    let stop_request = Arc::new(AtomicBool::new(false));
+
+    let progress = Arc::new(Progress::new());
+
+    let mut events = Events::new();
+    events.subscribe(event_printer(Arc::clone(&progress)));
+
+    let (a, b) = std::thread::scope(|s| {
+        let a = s.spawn(|| {
+            make_snapshot(
+                a_driver.as_ref(),
+                Side::Source,
+                a_dir.clone(),
+                &filters,
+                Arc::clone(&stop_request),
+                &events,
+            )
+        });
+
+        let b = s.spawn(|| {
+            make_snapshot(
+                b_driver.as_ref(),
+                Side::Destination,
+                b_dir.clone(),
+                &filters,
+                Arc::clone(&stop_request),
+                &events,
+            )
+        });
+
+        let (a, b) = (join_thread(a), join_thread(b));
+
+        progress.finish();
+
+        Ok::<_, anyhow::Error>((
+            a.with_context(|| format!("Snapshot of {} failed", a_dir))?,
+            b.with_context(|| format!("Snapshot of {} failed", b_dir))?,
+        ))
+    })?;
+
+    info!("Comparing both replicas with the baseline...");
+
+    let diff = build_three_way_diff(&baseline, a, b, &diff_options);
+
+    if args.format == OutputFormat::Csv {
+        let items = diff.into_items().into_iter();
+
+        match &args.output {
+            Some(output) => {
+                let file = fs::File::create(output)
+                    .with_context(|| format!("Failed to create output file: {}", output))?;
+
+                write_three_way_csv_report(items, BufWriter::new(file))
+                    .with_context(|| format!("Failed to write CSV output to: {}", output))?;
+
+                success!("CSV output written to: {}", output);
+            }
+            None => write_three_way_csv_report(items, BufWriter::new(stdout().lock()))
+                .context("Failed to write CSV output")?,
+        }
+
+        return Ok(());
+    }
+
+    if diff.is_empty() {
+        success!("Both replicas are identical to the baseline.");
+        return Ok(());
+    }
+
+    let items = diff.items();
+
+    let of_kind = |kind: fn(&ThreeWayStatus) -> bool| {
+        items
+            .iter()
+            .filter(|item| kind(&item.status))
+            .collect::<Vec<_>>()
+    };
+
+    let changed_in_a = of_kind(|status| matches!(status, ThreeWayStatus::ChangedInA(_)));
+    let changed_in_b = of_kind(|status| matches!(status, ThreeWayStatus::ChangedInB(_)));
+    let changed_in_both = of_kind(|status| matches!(status, ThreeWayStatus::ChangedInBoth { .. }));
+    let conflicts = of_kind(|status| matches!(status, ThreeWayStatus::Conflict { .. }));
+
+    print_changes(
+        &format!("Changed in {} only:", a_dir),
+        &changed_in_a,
+        |item| item.status.a(),
+    );
+    print_changes(
+        &format!("Changed in {} only:", b_dir),
+        &changed_in_b,
+        |item| item.status.b(),
+    );
+    print_changes("Changed identically in both:", &changed_in_both, |item| {
+        item.status.a()
+    });
+
+    if !conflicts.is_empty() {
+        warn!("Conflicts:");
+
+        for item in &conflicts {
+            warn!(
+                " {} ({}: {}, {}: {})",
+                item.path,
+                a_dir,
+                describe_change(item.status.a()),
+                b_dir,
+                describe_change(item.status.b())
+            );
+        }
+
+        warn!("");
+    }
+
+    info!(
+        "Found {} paths changed in {} only, {} in {} only, {} changed identically and {} conflicts.",
+        changed_in_a.len().to_string().bright_yellow(),
+        a_dir,
+        changed_in_b.len().to_string().bright_yellow(),
+        b_dir,
+        changed_in_both.len().to_string().bright_yellow(),
+        conflicts.len().to_string().bright_red()
+    );
+
+    Ok(())
+}
+
+fn print_changes(
+    title: &str,
+    items: &[&ThreeWayItem],
+    change: impl Fn(&ThreeWayItem) -> Option<&DiffType>,
+) {
+    if items.is_empty() {
+        return;
+    }
+
+    info!("{}", title);
+
+    for item in items {
+        let change = change(item);
+        let message = format!(" {} ({})", item.path, describe_change(change));
+
+        match change {
+            Some(DiffType::Added(_)) => println!("{}", message.bright_green()),
+            Some(DiffType::Deleted(_)) => println!("{}", message.bright_red()),
+            _ => println!("{}", message.bright_yellow()),
+        }
+    }
+
+    println!();
+}
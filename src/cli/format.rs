@@ -0,0 +1,138 @@
+use std::{sync::Arc, time::Duration};
+
+use differ_backup::{
+    diffing::DiffType,
+    drivers::{
+        DriverFileMetadata, DriverItemMetadata, DriverItemPermissions, DriverSpecialMetadata,
+        SizeMode,
+    },
+};
+
+pub fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut bytes = bytes as f64 / 1024.0;
+
+    if bytes < 1024.0 {
+        return format!("{:.2} KiB", bytes);
+    }
+
+    bytes /= 1024.0;
+
+    if bytes < 1024.0 {
+        return format!("{:.2} MiB", bytes);
+    }
+
+    format!("{:.2} GiB", bytes / 1024.0)
+}
+
+pub fn file_size(metadata: &DriverFileMetadata, mode: SizeMode) -> String {
+    let size = human_size(metadata.size_with(mode));
+
+    if metadata.is_sparse() {
+        format!("{}, sparse", size)
+    } else {
+        size
+    }
+}
+
+pub fn display_mode(mode: Option<u32>) -> String {
+    match mode {
+        Some(mode) => format!("{:04o}", mode),
+        None => "?".to_string(),
+    }
+}
+
+pub fn type_letter(metadata: DriverItemMetadata) -> &'static str {
+    match metadata {
+        DriverItemMetadata::Directory => "D",
+        DriverItemMetadata::File(_) => "F",
+        DriverItemMetadata::Special(DriverSpecialMetadata::Fifo) => "P",
+        DriverItemMetadata::Special(DriverSpecialMetadata::Socket) => "S",
+        DriverItemMetadata::Special(DriverSpecialMetadata::BlockDevice { .. }) => "B",
+        DriverItemMetadata::Special(DriverSpecialMetadata::CharDevice { .. }) => "C",
+    }
+}
+
+pub fn type_name(metadata: DriverItemMetadata) -> &'static str {
+    match metadata {
+        DriverItemMetadata::Directory => "directory",
+        DriverItemMetadata::File(_) => "file",
+        DriverItemMetadata::Special(m) => m.name(),
+    }
+}
+
+/// Display the owner as `user:group`, with names when they are known and IDs otherwise
+pub fn display_owner(permissions: &DriverItemPermissions) -> String {
+    let display = |name: &Option<Arc<str>>, id: Option<u32>| match (name, id) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(id)) => id.to_string(),
+        (None, None) => "?".to_string(),
+    };
+
+    format!(
+        "{}:{}",
+        display(&permissions.user, permissions.uid),
+        display(&permissions.group, permissions.gid)
+    )
+}
+
+/// Format a duration for humans (e.g. `1h 02m 03s`)
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!(
+            "{}h {:02}m {:02}s",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        ),
+    }
+}
+
+/// Format a Unix timestamp as an ISO-8601 date in UTC (e.g. `2022-08-31T13:37:00Z`)
+pub fn format_iso8601(timestamp: i64, nanos: Option<u32>) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+
+    // Convert days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let fraction = match nanos {
+        Some(nanos) if nanos > 0 => format!(".{:09}", nanos),
+        _ => String::new(),
+    };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        fraction
+    )
+}
+
+/// Describe the change made to one side of a three-way comparison (`None` being a directory
+/// which only changed inside)
+pub fn describe_change(change: Option<&DiffType>) -> String {
+    match change {
+        Some(change) => change.name().replace('-', " "),
+        None => "changed inside".to_string(),
+    }
+}
@@ -0,0 +1,365 @@
+use std::fmt::Write;
+
+use differ_backup::{
+    diffing::{CategorizedDiff, DiffOptions, DiffTreeChange, DiffTreeNode},
+    drivers::{DriverItemMetadata, ItemPath, SizeMode},
+};
+
+use super::format::{
+    display_mode, display_owner, format_iso8601, human_size, type_letter, type_name,
+};
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.5em; }
+h2 { font-size: 1.2em; margin-top: 2em; }
+table { border-collapse: collapse; margin-top: 0.5em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; }
+th { background: #f0f0f0; }
+td.path, li { font-family: monospace; }
+td.size { text-align: right; }
+ul { list-style: none; padding-left: 1.2em; }
+summary { cursor: pointer; }
+#filter { width: 30em; padding: 0.3em; }
+.added { color: #1a7f37; }
+.modified, .type-changed, .metadata-changed, .hard-linked { color: #9a6700; }
+.deleted { color: #cf222e; }
+.details { color: #666; }
+"#;
+
+const SCRIPT: &str = r#"
+document.getElementById('filter').addEventListener('input', function () {
+    var query = this.value.toLowerCase();
+    var matches = function (el) {
+        return query === '' || el.dataset.path.toLowerCase().indexOf(query) !== -1;
+    };
+
+    document.querySelectorAll('tr[data-path]').forEach(function (row) {
+        row.hidden = !matches(row);
+    });
+
+    // Deepest items first, so directories are shown when one of their children matches
+    var items = Array.prototype.slice.call(document.querySelectorAll('#tree li')).reverse();
+
+    items.forEach(function (li) {
+        var children = li.querySelectorAll(':scope > details > ul > li');
+        var visible = matches(li) || Array.prototype.some.call(children, function (child) {
+            return !child.hidden;
+        });
+
+        li.hidden = !visible;
+
+        var details = li.querySelector(':scope > details');
+
+        if (details && query !== '') {
+            details.open = visible;
+        }
+    });
+});
+"#;
+
+/// Render a self-contained HTML report of the diff
+pub fn render_html_report(
+    cat: &CategorizedDiff,
+    source_dir: &str,
+    dest_dir: &str,
+    diff_options: &DiffOptions,
+    size_mode: SizeMode,
+) -> String {
+    let mut html = String::new();
+    let totals = cat.totals(size_mode);
+
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Differences report</title>\n");
+    writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE).unwrap();
+
+    writeln!(
+        html,
+        "<h1>Differences between <code>{}</code> and <code>{}</code></h1>",
+        escape(source_dir),
+        escape(dest_dir)
+    )
+    .unwrap();
+
+    html.push_str("<h2>Summary</h2>\n<table>\n");
+
+    for (name, value) in [
+        ("Items to transfer", totals.transfer_count.to_string()),
+        ("Items to delete", totals.delete_count.to_string()),
+        ("Size to transfer", human_size(totals.transfer_size)),
+        ("Added", cat.added.len().to_string()),
+        ("Modified", cat.modified.len().to_string()),
+        ("Metadata changed", cat.metadata_changed.len().to_string()),
+        ("Hard linked", cat.hard_linked.len().to_string()),
+        ("Type changed", cat.type_changed.len().to_string()),
+        ("Deleted", cat.deleted.len().to_string()),
+    ] {
+        writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value).unwrap();
+    }
+
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<p><input id=\"filter\" type=\"search\" placeholder=\"Filter by path...\"></p>\n",
+    );
+
+    html.push_str("<h2>Tree</h2>\n<ul id=\"tree\">\n");
+    render_tree_children(&mut html, &cat.to_tree(size_mode).root, "", size_mode);
+    html.push_str("</ul>\n");
+
+    let mut table = Table::new(&mut html, size_mode);
+
+    table.start("Added", "added", cat.added.len());
+    for (path, added) in &cat.added {
+        table.row(path, None, Some(added.new), "");
+    }
+    table.end();
+
+    table.start("Modified", "modified", cat.modified.len());
+    for (path, modified) in &cat.modified {
+        table.row(
+            path,
+            Some(DriverItemMetadata::File(modified.prev)),
+            Some(DriverItemMetadata::File(modified.new)),
+            "",
+        );
+    }
+    table.end();
+
+    table.start(
+        "Metadata changed",
+        "metadata-changed",
+        cat.metadata_changed.len(),
+    );
+    for (path, changed) in &cat.metadata_changed {
+        let mut changes = vec![];
+
+        if diff_options.compare_permissions && changed.prev.mode != changed.new.mode {
+            changes.push(format!(
+                "{} => {}",
+                display_mode(changed.prev.mode),
+                display_mode(changed.new.mode)
+            ));
+        }
+
+        if diff_options.compare_ownership
+            && (changed.prev.uid != changed.new.uid || changed.prev.gid != changed.new.gid)
+        {
+            changes.push(format!(
+                "{} => {}",
+                display_owner(&changed.prev),
+                display_owner(&changed.new)
+            ));
+        }
+
+        if changed.xattrs_changed {
+            changes.push("extended attributes".to_string());
+        }
+
+        table.row(
+            path,
+            Some(changed.item),
+            Some(changed.item),
+            &changes.join(", "),
+        );
+    }
+    table.end();
+
+    table.start("Hard linked", "hard-linked", cat.hard_linked.len());
+    for (path, linked) in &cat.hard_linked {
+        table.row(
+            path,
+            linked.prev,
+            Some(DriverItemMetadata::File(linked.new)),
+            &format!("=> {}", linked.target),
+        );
+    }
+    table.end();
+
+    table.start("Type changed", "type-changed", cat.type_changed.len());
+    for (path, type_changed) in &cat.type_changed {
+        table.row(
+            path,
+            Some(type_changed.prev),
+            Some(type_changed.new),
+            &format!(
+                "{} => {}",
+                type_letter(type_changed.prev),
+                type_letter(type_changed.new)
+            ),
+        );
+    }
+    table.end();
+
+    table.start("Deleted", "deleted", cat.deleted.len());
+    for (path, deleted) in &cat.deleted {
+        table.row(path, Some(deleted.prev), None, "");
+    }
+    table.end();
+
+    writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT).unwrap();
+
+    html
+}
+
+fn render_tree_children(html: &mut String, node: &DiffTreeNode, parent: &str, size_mode: SizeMode) {
+    for child in node.children.values() {
+        let path = if parent.is_empty() {
+            child.name.to_string()
+        } else {
+            format!("{}/{}", parent, child.name)
+        };
+
+        let (class, is_dir) = match child.change {
+            Some(change) => (
+                match change {
+                    DiffTreeChange::Added(_) => "added",
+                    DiffTreeChange::Modified(_) => "modified",
+                    DiffTreeChange::TypeChanged { .. } => "type-changed",
+                    DiffTreeChange::MetadataChanged(_) => "metadata-changed",
+                    DiffTreeChange::HardLinked(_) => "hard-linked",
+                    DiffTreeChange::Deleted(_) => "deleted",
+                },
+                change.metadata().is_dir(),
+            ),
+            None => ("", true),
+        };
+
+        let mut details = vec![];
+
+        match child.change {
+            Some(DiffTreeChange::MetadataChanged(_)) => details.push("metadata".to_string()),
+            Some(DiffTreeChange::HardLinked(_)) => details.push("hard link".to_string()),
+            Some(change) => {
+                if let Some(size) = change.size_with(size_mode) {
+                    details.push(human_size(size));
+                }
+            }
+            None => {}
+        }
+
+        if child.collapsed.count > 0 {
+            details.push(format!(
+                "{} items, {}",
+                child.collapsed.count,
+                human_size(child.collapsed.size)
+            ));
+        }
+
+        let label = format!(
+            "<span class=\"{}\">{}{}</span>{}",
+            class,
+            escape(&child.name.to_string()),
+            if is_dir { "/" } else { "" },
+            if details.is_empty() {
+                String::new()
+            } else {
+                format!(" <span class=\"details\">({})</span>", details.join(", "))
+            }
+        );
+
+        write!(html, "<li data-path=\"{}\">", escape(&path)).unwrap();
+
+        if child.children.is_empty() {
+            html.push_str(&label);
+        } else {
+            writeln!(html, "<details open><summary>{}</summary>\n<ul>", label).unwrap();
+            render_tree_children(html, child, &path, size_mode);
+            html.push_str("</ul></details>");
+        }
+
+        html.push_str("</li>\n");
+    }
+}
+
+/// Per-category table of items
+struct Table<'a> {
+    html: &'a mut String,
+    size_mode: SizeMode,
+    class: &'static str,
+}
+
+impl<'a> Table<'a> {
+    fn new(html: &'a mut String, size_mode: SizeMode) -> Self {
+        Self {
+            html,
+            size_mode,
+            class: "",
+        }
+    }
+
+    fn start(&mut self, title: &str, class: &'static str, count: usize) {
+        self.class = class;
+
+        writeln!(
+            self.html,
+            "<h2 class=\"{}\">{} ({})</h2>\n<table>\n<tr><th>Path</th><th>Type</th><th>Old size</th><th>New size</th><th>Old modification date</th><th>New modification date</th><th>Details</th></tr>",
+            class, title, count
+        )
+        .unwrap();
+    }
+
+    fn row(
+        &mut self,
+        path: &ItemPath,
+        prev: Option<DriverItemMetadata>,
+        new: Option<DriverItemMetadata>,
+        details: &str,
+    ) {
+        let path = path.to_string();
+
+        let item_type = match (prev, new) {
+            (_, Some(m)) | (Some(m), None) => type_name(m),
+            (None, None) => "",
+        };
+
+        let size = |m: Option<DriverItemMetadata>| {
+            m.and_then(|m| m.size_with(self.size_mode))
+                .map(human_size)
+                .unwrap_or_default()
+        };
+
+        let mtime = |m: Option<DriverItemMetadata>| match m {
+            Some(DriverItemMetadata::File(m)) => {
+                format_iso8601(m.modification_date, m.modification_date_nanos)
+            }
+            _ => String::new(),
+        };
+
+        writeln!(
+            self.html,
+            "<tr data-path=\"{}\"><td class=\"path {}\">{}</td><td>{}</td><td class=\"size\">{}</td><td class=\"size\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&path),
+            self.class,
+            escape(&path),
+            item_type,
+            size(prev),
+            size(new),
+            mtime(prev),
+            mtime(new),
+            escape(details)
+        )
+        .unwrap();
+    }
+
+    fn end(&mut self) {
+        self.html.push_str("</table>\n");
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -1,3 +1,16 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Send informational messages to stderr, so stdout only contains the program's output (e.g. CSV)
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_stdout_reserved() -> bool {
+    STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
 #[macro_export]
 macro_rules! fail {
     ($message: tt, $($params: tt)*) => {{
@@ -39,7 +52,13 @@ macro_rules! warn {
 macro_rules! info {
     ($message: tt, $($params: tt)*) => {{
         use colored::Colorize;
-        println!("{}", format!($message, $($params)*).bright_blue());
+        let message = format!($message, $($params)*).bright_blue();
+
+        if $crate::cli::logging::is_stdout_reserved() {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
     }};
 
     ($message: tt) => {{
@@ -51,7 +70,13 @@ macro_rules! info {
 macro_rules! info_inline {
     ($message: tt, $($params: tt)*) => {{
         use colored::Colorize;
-        print!("{}", format!($message, $($params)*).bright_blue());
+        let message = format!($message, $($params)*).bright_blue();
+
+        if $crate::cli::logging::is_stdout_reserved() {
+            eprint!("{}", message);
+        } else {
+            print!("{}", message);
+        }
     }};
 
     ($message: tt) => {{
@@ -63,7 +88,13 @@ macro_rules! info_inline {
 macro_rules! success {
     ($message: tt, $($params: tt)*) => {{
         use colored::Colorize;
-        println!("{}", format!($message, $($params)*).bright_green());
+        let message = format!($message, $($params)*).bright_green();
+
+        if $crate::cli::logging::is_stdout_reserved() {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
     }};
 
     ($message: tt) => {{
@@ -1,5 +1,11 @@
-pub(self) mod cmd;
-pub(self) mod logging;
+mod bidirectional;
+mod cmd;
+mod csv;
+mod diff3;
+mod format;
+mod html;
+pub(crate) mod logging;
 mod program;
+mod progress;
 
 pub use program::main;
@@ -1,21 +1,42 @@
 use std::collections::HashSet;
-use std::io::{stdout, Write};
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fs::{self, canonicalize};
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::Instant;
-
-use super::cmd::Args;
-use crate::drivers::OnItemHandler;
-use crate::drivers::{sftp::SftpDriver, Driver};
-use crate::{
-    diffing::{build_diff, CategorizedDiff},
-    drivers::{fs::FsDriver, make_snapshot, DriverItemMetadata},
-};
-use crate::{info, success};
+use std::thread::ScopedJoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::bidirectional::sync_bidirectional;
+use super::cmd::{Args, Command, OutputFormat, SnapshotArgs};
+use super::csv::{write_csv_report, CsvReport};
+use super::diff3::diff3_main;
+use super::format::{display_mode, display_owner, file_size, human_size, type_letter};
+use super::html::render_html_report;
+use super::logging::reserve_stdout;
+use super::progress::Progress;
+use crate::{error, info, success, warn};
 use anyhow::{anyhow, bail, Context, Error, Result};
 use clap::StructOpt;
 use colored::Colorize;
+use differ_backup::drivers::{
+    merged::{MergedDriver, MergedRoot},
+    sftp::SftpDriver,
+    Driver, ItemPath,
+};
+use differ_backup::{
+    diffing::{
+        build_diff, filter_identical_checksums, find_name_collisions, stream_diff, CategorizedDiff,
+        CompareMode, Diff, DiffItem, DiffItemMetadataChanged, DiffOptions, DiffTreeChange,
+        DiffTreeNode, MtimeOffset, MtimeOffsetDetector, SafetyChecks,
+    },
+    drivers::{
+        fs::FsDriver, make_snapshot, BandwidthSchedule, DriverItemMetadata, DriverItemPermissions,
+        DriverPools, SizeMode, Snapshot, Throttle, WalkFilters,
+    },
+    events::{DiffPhase, Event, Events, Side},
+    sync::Replica,
+};
 
 pub fn main() {
     if let Err(err) = inner_main() {
@@ -24,27 +45,13 @@ pub fn main() {
     }
 }
 
-fn human_size(bytes: u64) -> String {
-    if bytes < 1024 {
-        return format!("{} B", bytes);
-    }
-
-    let mut bytes = bytes as f64 / 1024.0;
-
-    if bytes < 1024.0 {
-        return format!("{:.2} KiB", bytes);
-    }
-
-    bytes /= 1024.0;
-
-    if bytes < 1024.0 {
-        return format!("{:.2} MiB", bytes);
-    }
-
-    format!("{:.2} GiB", bytes / 1024.0)
-}
-
-fn driver_from_arg(arg: &str) -> Result<(Box<dyn Driver + Send + Sync>, String)> {
+pub(super) fn driver_from_arg(
+    arg: &str,
+    fs_driver: FsDriver,
+    read_throttle: Option<Arc<Throttle>>,
+    write_throttle: Option<Arc<Throttle>>,
+    pools: DriverPools,
+) -> Result<(Box<dyn Driver + Send + Sync>, String)> {
     if let Some(arg) = arg.strip_prefix("sftp:") {
         let mut parts = arg.split('|');
         let mut split = parts
@@ -81,30 +88,301 @@ fn driver_from_arg(arg: &str) -> Result<(Box<dyn Driver + Send + Sync>, String)>
         }
 
         return Ok((
-            Box::new(SftpDriver::connect(
-                address,
-                username,
-                Path::new(pub_key_path),
-                Path::new(priv_key_path),
-            )?),
+            Box::new(
+                SftpDriver::connect(
+                    address,
+                    username,
+                    Path::new(pub_key_path),
+                    Path::new(priv_key_path),
+                )?
+                .with_read_throttle(read_throttle)
+                .with_write_throttle(write_throttle)
+                .with_pools(pools),
+            ),
             path,
         ));
     }
 
-    Ok((Box::new(FsDriver::new()), arg.to_string()))
+    Ok((
+        Box::new(
+            fs_driver
+                .with_read_throttle(read_throttle)
+                .with_write_throttle(write_throttle)
+                .with_pools(pools),
+        ),
+        arg.to_string(),
+    ))
 }
 
 fn inner_main() -> Result<()> {
     let cmd = Args::parse();
 
-    let (source_driver, source_dir) = driver_from_arg(&cmd.source_dir)?;
-    let (dest_driver, dest_dir) = driver_from_arg(&cmd.dest_dir)?;
+    match cmd.command {
+        Some(Command::Diff3(args)) => return diff3_main(args),
+        Some(Command::Snapshot(args)) => return snapshot_main(args),
+        None => {}
+    }
+
+    // With mapped source directories, all positional arguments are destinations
+    let (source_args, dest_args) = if cmd.mappings.is_empty() {
+        (
+            cmd.source_dir.iter().cloned().collect::<Vec<_>>(),
+            cmd.dest_dirs.clone(),
+        )
+    } else {
+        (
+            cmd.mappings
+                .iter()
+                .map(|mapping| mapping.source.clone())
+                .collect(),
+            cmd.source_dir
+                .iter()
+                .chain(&cmd.dest_dirs)
+                .cloned()
+                .collect(),
+        )
+    };
+
+    if source_args.is_empty() || dest_args.is_empty() {
+        bail!("Please provide a source and at least one destination directory");
+    }
+    let fan_out = dest_args.len() > 1;
+
+    if cmd.format == OutputFormat::Csv {
+        if cmd.apply_metadata || cmd.apply_hard_links || cmd.apply_special {
+            bail!("Changes cannot be applied when using the CSV output format");
+        }
+
+        if cmd.output.is_none() {
+            reserve_stdout();
+        }
+    } else if cmd.output.is_some() {
+        bail!("An output file can only be provided for the CSV output format");
+    }
+
+    if cmd.bidirectional {
+        if cmd.format == OutputFormat::Csv || cmd.html_report.is_some() {
+            bail!("Reports are not available for bidirectional synchronization");
+        }
+
+        if cmd.apply_metadata || cmd.apply_hard_links || cmd.apply_special {
+            bail!("Only file contents and directories are synchronized in bidirectional mode");
+        }
+
+        if cmd.mtime_offset != 0 {
+            bail!("A modification time offset cannot be used in bidirectional mode");
+        }
+    }
+
+    if fan_out {
+        if cmd.format == OutputFormat::Csv || cmd.html_report.is_some() {
+            bail!("Reports can only be written for a single destination");
+        }
+
+        if cmd.bidirectional {
+            bail!("Bidirectional synchronization only supports a single destination");
+        }
+    }
 
-    let ignore = cmd
-        .ignore
+    let mut excluded_paths = cmd
+        .exclude_mount
         .iter()
-        .map(|s| s.as_str())
-        .collect::<HashSet<_>>();
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+    // Never descend into a destination when it lies inside a source
+    for source_arg in source_args.iter().filter(|arg| !arg.starts_with("sftp:")) {
+        let Ok(source) = canonicalize(source_arg) else {
+            continue;
+        };
+
+        for dest_arg in dest_args.iter().filter(|arg| !arg.starts_with("sftp:")) {
+            if let Ok(dest) = canonicalize(dest_arg) {
+                if dest.starts_with(&source) {
+                    warn!(
+                        "Warning: destination directory {} is inside the source directory {}, it will be excluded from the source's snapshot.",
+                        dest_arg,
+                        source_arg
+                    );
+
+                    excluded_paths.push(dest);
+                }
+            }
+        }
+    }
+
+    let fs_driver = || {
+        FsDriver::new()
+            .with_xattrs(cmd.xattrs)
+            .with_one_file_system(cmd.one_file_system)
+            .with_included_mounts(cmd.include_mount.iter().map(PathBuf::from).collect())
+    };
+
+    // Exclusions only apply to sources, as a destination would otherwise exclude itself
+    let source_fs_driver = || fs_driver().with_excluded_paths(excluded_paths.clone());
+
+    let throttle = |schedule: &Option<BandwidthSchedule>| {
+        schedule
+            .clone()
+            .map(|schedule| Arc::new(Throttle::new(schedule)))
+    };
+
+    if cmd.read_limit.is_some() && source_args.iter().any(|arg| arg.starts_with("sftp:")) {
+        warn!("Warning: read limit only applies to a source on the local filesystem, it will be ignored.");
+    }
+
+    // Shared by all local sources, so it limits their total throughput
+    let read_throttle = throttle(&cmd.read_limit);
+
+    let source_throttle = |source_arg: &str| {
+        if source_arg.starts_with("sftp:") {
+            None
+        } else {
+            read_throttle.clone()
+        }
+    };
+
+    let source_pools = DriverPools::new(cmd.source_scan_threads, cmd.source_transfer_threads)?;
+
+    // Mapped source directories are merged into a single namespace mirroring the destination
+    let (source_driver, source_dir, mapped_prefixes) = if cmd.mappings.is_empty() {
+        let (driver, dir) = driver_from_arg(
+            &source_args[0],
+            source_fs_driver(),
+            source_throttle(&source_args[0]),
+            None,
+            source_pools,
+        )?;
+
+        (driver, dir, None)
+    } else {
+        let mut roots = vec![];
+
+        for mapping in &cmd.mappings {
+            let (driver, dir) = driver_from_arg(
+                &mapping.source,
+                source_fs_driver(),
+                source_throttle(&mapping.source),
+                None,
+                source_pools.clone(),
+            )?;
+
+            roots.push(MergedRoot {
+                prefix: ItemPath::new(mapping.subdir.clone().into_bytes()),
+                driver,
+                dir,
+            });
+        }
+
+        let label = roots
+            .iter()
+            .map(|root| format!("{}={}", root.dir, root.prefix))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let merged = MergedDriver::new(roots)?;
+        let prefixes = merged.prefixes();
+
+        (
+            Box::new(merged) as Box<dyn Driver + Send + Sync>,
+            label,
+            Some(prefixes),
+        )
+    };
+
+    // Destinations which could not be processed, when there are several of them
+    let mut failures = vec![];
+
+    let mut dests = vec![];
+
+    // Each destination gets its own pools and bandwidth limit
+    for dest_arg in &dest_args {
+        let dest_pools = DriverPools::new(cmd.dest_scan_threads, cmd.dest_transfer_threads)?;
+
+        match driver_from_arg(
+            dest_arg,
+            fs_driver(),
+            None,
+            throttle(&cmd.bwlimit),
+            dest_pools,
+        ) {
+            Ok((driver, dir)) => dests.push(Destination {
+                arg: dest_arg.clone(),
+                driver,
+                dir,
+            }),
+            Err(err) => destination_failed(&mut failures, dest_arg, err, fan_out)?,
+        }
+    }
+
+    if cmd.xattrs {
+        if !source_driver.supports_xattrs() {
+            warn!("Warning: source driver cannot read extended attributes, they will be ignored.");
+        } else if dests.iter().any(|dest| !dest.driver.supports_xattrs()) {
+            warn!(
+                "Warning: destination driver cannot store extended attributes, they will be lost."
+            );
+        }
+    }
+
+    if let (Some(min), Some(max)) = (cmd.min_size, cmd.max_size) {
+        if min > max {
+            bail!("Minimum file size cannot be greater than the maximum file size");
+        }
+    }
+
+    let now: i64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System clock is set before the Unix epoch")?
+        .as_secs()
+        .try_into()
+        .context("System clock is too far in the future")?;
+
+    let timestamp_ago = |age: u64| now.saturating_sub(age.try_into().unwrap_or(i64::MAX));
+
+    let filters = WalkFilters {
+        ignore: cmd.ignore.iter().cloned().collect(),
+        max_depth: cmd.max_depth,
+        min_size: cmd.min_size,
+        max_size: cmd.max_size,
+        modified_after: cmd.newer_than.map(timestamp_ago),
+        modified_before: cmd.older_than.map(timestamp_ago),
+    };
+
+    let walk_filters = filters.location_only();
+
+    if let Some(percent) = cmd.max_delete_percent {
+        if !(0.0..=100.0).contains(&percent) {
+            bail!("Maximum percentage of deletions must be between 0 and 100");
+        }
+    }
+
+    if !cmd.mtime_tolerance.is_finite() || cmd.mtime_tolerance < 0.0 {
+        bail!("Modification time tolerance must be a positive number of seconds");
+    }
+
+    let diff_options = DiffOptions {
+        compare: cmd.compare,
+        mtime_tolerance: Duration::from_secs_f64(cmd.mtime_tolerance),
+        mtime_offset: cmd.mtime_offset,
+        compare_permissions: cmd.permissions,
+        compare_ownership: cmd.ownership,
+        compare_xattrs: cmd.xattrs,
+        hard_links: cmd.hard_links,
+        skip_special: cmd.skip_special,
+        normalization: cmd.normalize,
+        case_insensitive: cmd.case_insensitive,
+    };
+
+    let size_mode = cmd.size_mode;
+
+    let safety = SafetyChecks {
+        max_delete: cmd.max_delete,
+        max_delete_percent: cmd.max_delete_percent,
+        allow_empty_source: cmd.allow_empty_source,
+        source_marker: cmd.source_marker.clone(),
+        dest_marker: cmd.dest_marker.clone(),
+    };
 
     info!("Building snapshots for source and destination...");
 
@@ -112,86 +390,824 @@ fn inner_main() -> Result<()> {
 
     let stop_request = Arc::new(AtomicBool::new(false));
 
-    let (source, dest) = std::thread::scope(|s| {
-        let (source_update, dest_update) = items_spinner();
+    let progress = Arc::new(Progress::new());
+
+    let mut events = Events::new();
+    events.subscribe(event_printer(Arc::clone(&progress)));
+
+    // The source is only walked once, whatever the number of destinations
+    let (source, dest_snapshots) = std::thread::scope(|s| {
+        let (filters, events) = (&walk_filters, &events);
 
         let source = s.spawn(|| {
             make_snapshot(
                 source_driver.as_ref(),
-                source_dir,
-                &ignore,
+                Side::Source,
+                source_dir.clone(),
+                filters,
                 Arc::clone(&stop_request),
-                Some(source_update),
+                events,
             )
         });
 
-        let dest = s.spawn(|| {
-            make_snapshot(
-                dest_driver.as_ref(),
-                dest_dir,
-                &ignore,
-                Arc::clone(&stop_request),
-                Some(dest_update),
-            )
-        });
-
-        let err = |err: Error| -> String {
-            format!("{:?}", err)
-                .split('\n')
-                .map(|line| format!("    {}", line))
-                .collect::<Vec<_>>()
-                .join("\n")
-        };
-
-        let (source, dest) = (source.join().unwrap(), dest.join().unwrap());
-
-        if source.is_ok() && dest.is_ok() {
-            print!("\r");
-        } else {
-            println!();
-        }
-
-        match (source, dest) {
-            (Err(source), Err(dest)) => Err(anyhow!(
+        let dest_snapshots = dests
+            .iter()
+            .map(|dest| {
+                // A failing destination must not interrupt the others
+                let stop_request = if fan_out {
+                    Arc::new(AtomicBool::new(false))
+                } else {
+                    Arc::clone(&stop_request)
+                };
+
+                s.spawn(move || {
+                    make_snapshot(
+                        dest.driver.as_ref(),
+                        Side::Destination,
+                        dest.dir.clone(),
+                        filters,
+                        stop_request,
+                        events,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let source = join_thread(source);
+
+        let dest_snapshots = dest_snapshots
+            .into_iter()
+            .map(join_thread)
+            .collect::<Vec<_>>();
+
+        progress.finish();
+
+        (source, dest_snapshots)
+    });
+
+    let err = |err: &Error| -> String {
+        format!("{:?}", err)
+            .split('\n')
+            .map(|line| format!("    {}", line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    if !fan_out {
+        match (&source, &dest_snapshots[0]) {
+            (Err(source), Err(dest)) => bail!(
                 "Source snapshot failed:\n{}\n\nDestination snapshot failed:\n{}",
                 err(source).bright_yellow(),
                 err(dest).bright_yellow()
-            )),
+            ),
 
-            (Err(source), Ok(_)) => Err(anyhow!(
-                "Source snapshot failed:\n{}",
-                err(source).bright_yellow()
-            )),
+            (Err(source), Ok(_)) => {
+                bail!("Source snapshot failed:\n{}", err(source).bright_yellow())
+            }
 
-            (Ok(_), Err(dest)) => Err(anyhow!(
+            (Ok(_), Err(dest)) => bail!(
                 "Destination snapshot failed:\n{}",
                 err(dest).bright_yellow()
-            )),
+            ),
 
-            (Ok(source), Ok(dest)) => Ok((source, dest)),
+            (Ok(_), Ok(_)) => {}
         }
-    })?;
+    }
+
+    let mut source = match source {
+        Ok(source) => source,
+        Err(source) => bail!("Source snapshot failed:\n{}", err(&source).bright_yellow()),
+    };
+
+    // Files rejected by the size and modification date filters are left untouched on both sides,
+    // they are only removed after the safety checks so markers and emptiness are still detected
+    let mut rejected = filters.rejected_paths(&source);
+
+    let mut snapshots = vec![];
+
+    for (dest, snapshot) in dests.iter().zip(dest_snapshots) {
+        match snapshot.and_then(|mut snapshot| {
+            // Both directories are sources in bidirectional mode
+            if cmd.bidirectional {
+                rejected.extend(filters.rejected_paths(&snapshot));
+            }
+
+            // Parts of the destination which aren't mapped are never compared, so never deleted
+            if let Some(prefixes) = &mapped_prefixes {
+                snapshot.retain_mapped(prefixes);
+                safety.check_mappings(&source, &snapshot, prefixes)?;
+            }
+
+            safety.check_snapshots(&source, &snapshot)?;
+
+            snapshot.items.retain(|item| !rejected.contains(&item.path));
+            Ok(snapshot)
+        }) {
+            Ok(snapshot) => snapshots.push((dest, snapshot)),
+            Err(err) => destination_failed(&mut failures, &dest.arg, err, fan_out)?,
+        }
+    }
+
+    source.items.retain(|item| !rejected.contains(&item.path));
 
     info!(
-        "Found {} files in source and {} in destination in {}. Computing differences...",
+        "Found {} files in source and {} in {} in {}. Computing differences...",
         source.items.len().to_string().bright_yellow(),
-        dest.items.len().to_string().bright_yellow(),
+        snapshots
+            .iter()
+            .map(|(_, snapshot)| snapshot.items.len().to_string().bright_yellow().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        if fan_out {
+            "destinations"
+        } else {
+            "destination"
+        },
         format!("{}s", started.elapsed().as_secs()).bright_magenta()
     );
 
+    if let Some(baseline) = &cmd.baseline {
+        // Bidirectional synchronization is only allowed with a single destination
+        let (dest, dest_snapshot) = snapshots.remove(0);
+
+        return sync_bidirectional(
+            Replica {
+                driver: source_driver.as_ref(),
+                root: &source_dir,
+            },
+            source,
+            Replica {
+                driver: dest.driver.as_ref(),
+                root: &dest.dir,
+            },
+            dest_snapshot,
+            Path::new(baseline),
+            cmd.conflict_policy,
+            cmd.dry_run,
+            &diff_options,
+            &safety,
+            &progress,
+            &events,
+        );
+    }
+
+    let collisions = find_name_collisions(&source, &diff_options);
+
+    if !collisions.is_empty() {
+        warn!(
+            "Warning: found {} groups of source items whose names collide once normalized, only one of each will be compared:",
+            collisions.len()
+        );
+
+        for paths in &collisions {
+            let paths = paths
+                .iter()
+                .map(|path| path.to_string())
+                .collect::<Vec<_>>();
+            warn!(" {}", paths.join(" | "));
+        }
+
+        info!("");
+    }
+
     let started = Instant::now();
 
-    let mut diff = build_diff(source, dest);
+    // Progress of each diff is only displayed when there is a single one
+    let quiet = Events::new();
+    let diff_events = if fan_out { &quiet } else { &events };
+
+    // Rows are written as differences are found so huge diffs are never held in memory, except
+    // with hard links as they can only be resolved once the whole diff is known
+    if cmd.format == OutputFormat::Csv && !diff_options.hard_links {
+        // Reports can only be written for a single destination
+        let (dest, snapshot) = snapshots.remove(0);
+
+        let writer: Box<dyn Write> = match &cmd.output {
+            Some(output) => Box::new(BufWriter::new(
+                fs::File::create(output)
+                    .with_context(|| format!("Failed to create output file: {}", output))?,
+            )),
+            None => Box::new(BufWriter::new(stdout().lock())),
+        };
+
+        let offset = stream_csv_report(
+            &source,
+            source_driver.as_ref(),
+            &source_dir,
+            dest,
+            &snapshot,
+            &diff_options,
+            &events,
+            writer,
+            size_mode,
+        )
+        .with_context(|| match &cmd.output {
+            Some(output) => format!("Failed to write CSV output to: {}", output),
+            None => "Failed to write CSV output".to_string(),
+        })?;
+
+        if let Some(offset) = offset {
+            warn_mtime_offset(&offset, &diff_options, None);
+        }
+
+        if let Some(output) = &cmd.output {
+            success!("CSV output written to: {}", output);
+        }
 
-    if diff.is_empty() {
-        success!("Source and destination are completely identical, nothing to do!");
         return Ok(());
     }
 
-    diff.sort();
+    let diffs = std::thread::scope(|s| {
+        // The source snapshot is shared by all diffs
+        let (source, source_driver, source_dir, diff_options) =
+            (&source, source_driver.as_ref(), &source_dir, &diff_options);
+
+        let diffs = snapshots
+            .into_iter()
+            .map(|(dest, snapshot)| {
+                let diff = s.spawn(move || -> Result<_> {
+                    let dest_items = snapshot.items.len();
+
+                    let mut diff = build_diff(source, &snapshot, diff_options, diff_events);
+
+                    if diff_options.compare == CompareMode::Checksum {
+                        diff = filter_identical_checksums(
+                            diff,
+                            source_driver,
+                            source_dir,
+                            dest.driver.as_ref(),
+                            &dest.dir,
+                            diff_events,
+                        )?;
+                    }
+
+                    Ok((dest_items, diff))
+                });
+
+                (dest, diff)
+            })
+            .collect::<Vec<_>>();
+
+        diffs
+            .into_iter()
+            .map(|(dest, diff)| (dest, join_thread(diff)))
+            .collect::<Vec<_>>()
+    });
+
+    let mut reports = vec![];
+
+    for (dest, diff) in diffs {
+        let (dest_items, mut diff) = match diff {
+            Ok(diff) => diff,
+            Err(err) => {
+                destination_failed(&mut failures, &dest.arg, err, fan_out)?;
+                continue;
+            }
+        };
 
-    let cat = CategorizedDiff::new(diff);
+        if let Some(offset) = diff.detect_mtime_offset(&diff_options) {
+            warn_mtime_offset(&offset, &diff_options, fan_out.then_some(dest.arg.as_str()));
+        }
+
+        // Only reached with hard links, otherwise rows are written while the diff is computed
+        if cmd.format == OutputFormat::Csv {
+            diff.sort();
+
+            let items = diff.into_items().into_iter();
+
+            match &cmd.output {
+                Some(output) => {
+                    let file = fs::File::create(output)
+                        .with_context(|| format!("Failed to create output file: {}", output))?;
+
+                    write_csv_report(items, BufWriter::new(file), size_mode)
+                        .with_context(|| format!("Failed to write CSV output to: {}", output))?;
+
+                    success!("CSV output written to: {}", output);
+                }
+                None => write_csv_report(items, BufWriter::new(stdout().lock()), size_mode)
+                    .context("Failed to write CSV output")?,
+            }
+
+            return Ok(());
+        }
+
+        if diff.is_empty() && !fan_out {
+            success!("Source and destination are completely identical, nothing to do!");
+            return Ok(());
+        }
 
+        diff.sort();
+
+        reports.push((dest, dest_items, CategorizedDiff::new(diff)));
+    }
+
+    if fan_out {
+        print_destinations_summary(&reports, size_mode);
+    }
+
+    for (dest, dest_items, cat) in &reports {
+        if fan_out {
+            if cat.is_empty() {
+                success!("Destination {} is identical to the source.", dest.arg);
+                continue;
+            }
+
+            info!("Destination {}:", dest.arg.bright_yellow());
+        }
+
+        let result = report_and_apply(
+            &cmd,
+            cat,
+            Replica {
+                driver: source_driver.as_ref(),
+                root: &source_dir,
+            },
+            Replica {
+                driver: dest.driver.as_ref(),
+                root: &dest.dir,
+            },
+            *dest_items,
+            &diff_options,
+            &safety,
+            &progress,
+            started,
+        );
+
+        if let Err(err) = result {
+            destination_failed(&mut failures, &dest.arg, err, fan_out)?;
+        }
+
+        if fan_out {
+            info!("");
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "{} out of {} destinations failed: {}",
+            failures.len(),
+            dest_args.len(),
+            failures
+                .iter()
+                .map(|(dest, _)| dest.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Wait for a thread, a panic being turned into an error so it doesn't abort other threads
+pub(super) fn join_thread<T>(handle: ScopedJoinHandle<'_, Result<T>>) -> Result<T> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("Internal error: a thread panicked")))
+}
+
+/// Destination of a run, several of them can be synchronized with the same source
+struct Destination {
+    /// Argument the destination was provided as, used to designate it in messages
+    arg: String,
+    driver: Box<dyn Driver + Send + Sync>,
+    dir: String,
+}
+
+/// Report the failure of a destination, which is only fatal when it is the only one
+fn destination_failed(
+    failures: &mut Vec<(String, Error)>,
+    dest: &str,
+    err: Error,
+    fan_out: bool,
+) -> Result<()> {
+    if !fan_out {
+        return Err(err);
+    }
+
+    error!("Destination {} failed: {:?}", dest, err);
+    failures.push((dest.to_string(), err));
+
+    Ok(())
+}
+
+fn print_destinations_summary(
+    reports: &[(&Destination, usize, CategorizedDiff)],
+    size_mode: SizeMode,
+) {
+    let headers = [
+        "Destination",
+        "To transfer",
+        "To delete",
+        "Size",
+        "Metadata",
+        "Hard links",
+    ];
+
+    let rows = reports
+        .iter()
+        .map(|(dest, _, cat)| {
+            let totals = cat.totals(size_mode);
+
+            [
+                dest.arg.clone(),
+                totals.transfer_count.to_string(),
+                totals.delete_count.to_string(),
+                human_size(totals.transfer_size),
+                cat.metadata_changed.len().to_string(),
+                cat.hard_linked.len().to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let widths = headers.map(str::len);
+    let widths = rows.iter().fold(widths, |mut widths, row| {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+
+        widths
+    });
+
+    info!("Changes by destination:");
+
+    let line = |cells: [&str; 6]| {
+        cells
+            .iter()
+            .zip(widths)
+            .enumerate()
+            .map(|(i, (cell, width))| {
+                if i == 0 {
+                    format!("{:<width$}", cell, width = width)
+                } else {
+                    format!("{:>width$}", cell, width = width)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+
+    println!(" {}", line(headers).bold());
+
+    for row in &rows {
+        println!(" {}", line(row.each_ref().map(String::as_str)));
+    }
+
+    println!();
+}
+
+/// Warn about modification times shifted by whole hours, with the destination's argument in
+/// fan-out mode
+fn warn_mtime_offset(offset: &MtimeOffset, diff_options: &DiffOptions, dest: Option<&str>) {
+    warn!(
+        "Warning: {} out of {} modified files have their modification time shifted by exactly {} hour(s), which usually indicates a timezone or DST issue on the destination{}.",
+        offset.matching,
+        offset.candidates,
+        offset.offset / 3600,
+        dest.map(|dest| format!(" {}", dest)).unwrap_or_default()
+    );
+    warn!(
+        "If this is expected, use '--mtime-offset {}' to compensate.",
+        offset.offset.saturating_add(diff_options.mtime_offset)
+    );
+}
+
+/// Write the CSV report of a destination while the differences with it are computed, returning
+/// the modification time offset detected on the way
+#[allow(clippy::too_many_arguments)]
+fn stream_csv_report(
+    source: &Snapshot,
+    source_driver: &(dyn Driver + Sync),
+    source_dir: &str,
+    dest: &Destination,
+    dest_snapshot: &Snapshot,
+    diff_options: &DiffOptions,
+    events: &Events,
+    writer: impl Write,
+    size_mode: SizeMode,
+) -> Result<Option<MtimeOffset>> {
+    // Checksums are computed in parallel for batches of items
+    const CHECKSUM_BATCH: usize = 1024;
+
+    let checksum = diff_options.compare == CompareMode::Checksum;
+    let batch_size = if checksum { CHECKSUM_BATCH } else { 1 };
+
+    let mut report = CsvReport::new(writer, size_mode)?;
+    let mut detector = MtimeOffsetDetector::default();
+    let quiet = Events::new();
+
+    let mut write = |items: Vec<DiffItem>| -> Result<()> {
+        let items = if checksum {
+            filter_identical_checksums(
+                Diff::new(items),
+                source_driver,
+                source_dir,
+                dest.driver.as_ref(),
+                &dest.dir,
+                &quiet,
+            )?
+            .into_items()
+        } else {
+            items
+        };
+
+        for item in &items {
+            detector.add(item, diff_options);
+            report.write_item(item)?;
+        }
+
+        Ok(())
+    };
+
+    let mut batch = Vec::with_capacity(batch_size);
+
+    stream_diff(source, dest_snapshot, diff_options, events, |item| {
+        batch.push(item);
+
+        if batch.len() < batch_size {
+            return Ok(());
+        }
+
+        write(std::mem::replace(
+            &mut batch,
+            Vec::with_capacity(batch_size),
+        ))
+    })?;
+
+    write(batch)?;
+    report.finish()?;
+
+    Ok(detector.result())
+}
+
+/// Display the differences with a destination and apply the requested changes to it
+#[allow(clippy::too_many_arguments)]
+fn report_and_apply(
+    cmd: &Args,
+    cat: &CategorizedDiff,
+    source: Replica,
+    dest: Replica,
+    dest_items: usize,
+    diff_options: &DiffOptions,
+    safety: &SafetyChecks,
+    progress: &Progress,
+    started: Instant,
+) -> Result<()> {
+    let size_mode = cmd.size_mode;
+
+    match cmd.summary_depth {
+        Some(depth) => print_summary(cat, depth, size_mode),
+        None if cmd.tree => print_tree(cat, size_mode),
+        None => print_items(cat, diff_options, size_mode),
+    }
+
+    info!(
+        "Differences computed in {}.",
+        format!("{}s", started.elapsed().as_secs()).bright_magenta()
+    );
+
+    let totals = cat.totals(size_mode);
+
+    progress.set_planned(totals.transfer_count, totals.transfer_size);
+
+    info!(
+        "Found a total of {} items to transfer and {} to delete for a total of {}.",
+        totals.transfer_count.to_string().bright_green(),
+        totals.delete_count.to_string().bright_red(),
+        human_size(totals.transfer_size).bright_yellow()
+    );
+
+    if !cat.metadata_changed.is_empty() {
+        info!(
+            "Found {} items with metadata changes only.",
+            cat.metadata_changed.len().to_string().bright_yellow()
+        );
+    }
+
+    if !cat.hard_linked.is_empty() {
+        info!(
+            "Found {} items to create as hard links.",
+            cat.hard_linked.len().to_string().bright_yellow()
+        );
+    }
+
+    if let Some(report_path) = &cmd.html_report {
+        let report = render_html_report(cat, source.root, dest.root, diff_options, size_mode);
+
+        fs::write(report_path, report)
+            .with_context(|| format!("Failed to write HTML report to: {}", report_path))?;
+
+        success!("HTML report written to: {}", report_path);
+    }
+
+    safety.check_deletions(totals.delete_count, dest_items)?;
+
+    // Files whose content changed may also have had their permissions changed
+    let metadata_changes = cat
+        .metadata_changed
+        .iter()
+        .map(|(path, changed)| (path, changed))
+        .chain(cat.modified.iter().filter_map(|(path, modified)| {
+            modified
+                .metadata_changed
+                .as_ref()
+                .map(|changed| (path, changed))
+        }))
+        .collect::<Vec<_>>();
+
+    if cmd.apply_metadata && !metadata_changes.is_empty() {
+        info!("Applying metadata changes to destination...");
+
+        for (path, changed) in &metadata_changes {
+            // Only apply what was actually compared
+            let permissions = DriverItemPermissions {
+                mode: changed
+                    .new
+                    .mode
+                    .filter(|_| diff_options.compare_permissions),
+                uid: changed.new.uid.filter(|_| diff_options.compare_ownership),
+                gid: changed.new.gid.filter(|_| diff_options.compare_ownership),
+                user: None,
+                group: None,
+            };
+
+            if permissions != DriverItemPermissions::default() {
+                dest.driver
+                    .set_permissions(dest.root, cat.dest_path(path), &permissions)?;
+            }
+
+            if changed.xattrs_changed && dest.driver.supports_xattrs() {
+                let xattrs = source.driver.read_xattrs(source.root, path)?;
+                dest.driver
+                    .write_xattrs(dest.root, cat.dest_path(path), &xattrs)?;
+            }
+        }
+
+        success!(
+            "Applied metadata changes to {} items.",
+            metadata_changes.len()
+        );
+    }
+
+    if cmd.apply_hard_links && !cat.hard_linked.is_empty() {
+        if !dest.driver.supports_hard_links() {
+            bail!("Destination driver cannot create hard links");
+        }
+
+        // Links can only be created to targets which are already up to date in the destination
+        let pending = cat
+            .added
+            .iter()
+            .map(|(path, _)| path)
+            .chain(cat.modified.iter().map(|(path, _)| path))
+            .chain(cat.type_changed.iter().map(|(path, _)| path))
+            .chain(cat.hard_linked.iter().map(|(path, _)| path))
+            .collect::<HashSet<_>>();
+
+        info!("Creating hard links in destination...");
+
+        let mut created = 0;
+
+        for (path, linked) in &cat.hard_linked {
+            if pending.contains(&linked.target)
+                || matches!(linked.prev, Some(DriverItemMetadata::Directory))
+            {
+                continue;
+            }
+
+            let target = linked.dest_target.as_ref().unwrap_or(&linked.target);
+
+            dest.driver
+                .hard_link(dest.root, target, cat.dest_path(path))?;
+            created += 1;
+        }
+
+        success!(
+            "Created {} hard links ({} skipped as their target is not up to date yet).",
+            created,
+            cat.hard_linked.len() - created
+        );
+    }
+
+    if cmd.apply_special {
+        let specials = cat
+            .added
+            .iter()
+            .filter_map(|(path, added)| match added.new {
+                DriverItemMetadata::Special(special) => {
+                    Some((path, special, added.permissions.mode))
+                }
+                DriverItemMetadata::Directory | DriverItemMetadata::File(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !specials.is_empty() {
+            if !dest.driver.supports_special() {
+                bail!("Destination driver cannot create special files");
+            }
+
+            info!("Creating special files in destination...");
+
+            let mut created = 0;
+
+            for (path, special, mode) in &specials {
+                let mode = mode.unwrap_or(0o644);
+
+                // Creating device nodes usually requires privileges, so failures are not fatal
+                match dest.driver.create_special(dest.root, path, *special, mode) {
+                    Ok(()) => created += 1,
+                    Err(err) => warn!("Warning: skipped {}: {:?}", path, err),
+                }
+            }
+
+            success!(
+                "Created {} special files ({} skipped).",
+                created,
+                specials.len() - created
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn snapshot_main(args: SnapshotArgs) -> Result<()> {
+    let (driver, dir) = driver_from_arg(
+        &args.dir,
+        FsDriver::new(),
+        None,
+        None,
+        DriverPools::default(),
+    )?;
+
+    let filters = WalkFilters {
+        ignore: args.ignore.into_iter().collect(),
+        ..Default::default()
+    };
+
+    let progress = Arc::new(Progress::new());
+
+    let mut events = Events::new();
+    events.subscribe(event_printer(Arc::clone(&progress)));
+
+    info!("Building snapshot of {}...", dir);
+
+    let snapshot = make_snapshot(
+        driver.as_ref(),
+        Side::Source,
+        dir,
+        &filters,
+        Arc::new(AtomicBool::new(false)),
+        &events,
+    );
+
+    progress.finish();
+
+    let snapshot = snapshot?;
+    snapshot.save(Path::new(&args.output))?;
+
+    success!(
+        "Saved snapshot of {} items to: {}",
+        snapshot.items.len(),
+        args.output
+    );
+
+    Ok(())
+}
+
+/// Describe the permission, ownership and extended attribute changes of an item
+fn describe_metadata_changes(
+    changed: &DiffItemMetadataChanged,
+    diff_options: &DiffOptions,
+) -> Vec<String> {
+    let mut changes = vec![];
+
+    if diff_options.compare_permissions && changed.prev.mode != changed.new.mode {
+        changes.push(format!(
+            "{} => {}",
+            display_mode(changed.prev.mode),
+            display_mode(changed.new.mode)
+        ));
+    }
+
+    if diff_options.compare_ownership
+        && (changed.prev.uid != changed.new.uid || changed.prev.gid != changed.new.gid)
+    {
+        changes.push(format!(
+            "{} => {}",
+            display_owner(&changed.prev),
+            display_owner(&changed.new)
+        ));
+    }
+
+    if changed.xattrs_changed {
+        changes.push("extended attributes".to_string());
+    }
+
+    changes
+}
+
+fn print_items(cat: &CategorizedDiff, diff_options: &DiffOptions, size_mode: SizeMode) {
     if !cat.added.is_empty() {
         info!("Added:");
 
@@ -202,8 +1218,13 @@ fn inner_main() -> Result<()> {
                 }
                 DriverItemMetadata::File(m) => println!(
                     " {} {}",
-                    path.bright_green(),
-                    format!("({})", human_size(m.size)).bright_yellow()
+                    path.to_string().bright_green(),
+                    format!("({})", file_size(&m, size_mode)).bright_yellow()
+                ),
+                DriverItemMetadata::Special(m) => println!(
+                    " {} {}",
+                    path.to_string().bright_green(),
+                    format!("({})", m.name()).bright_yellow()
                 ),
             }
         }
@@ -215,9 +1236,50 @@ fn inner_main() -> Result<()> {
         info!("Modified:");
 
         for (path, modified) in &cat.modified {
+            let mut message = format!(" {} ({}", path, file_size(&modified.new, size_mode));
+
+            if let Some(changed) = &modified.metadata_changed {
+                for change in describe_metadata_changes(changed, diff_options) {
+                    message.push_str(", ");
+                    message.push_str(&change);
+                }
+            }
+
+            message.push(')');
+
+            println!("{}", message.bright_yellow());
+        }
+
+        println!();
+    }
+
+    if !cat.metadata_changed.is_empty() {
+        info!("Metadata changed:");
+
+        for (path, changed) in &cat.metadata_changed {
+            let changes = describe_metadata_changes(changed, diff_options);
+
+            let message = format!(
+                " {}{} ({})",
+                path,
+                if changed.item.is_dir() { "/" } else { "" },
+                changes.join(", ")
+            );
+
+            println!("{}", message.bright_yellow());
+        }
+
+        println!();
+    }
+
+    if !cat.hard_linked.is_empty() {
+        info!("Hard linked:");
+
+        for (path, linked) in &cat.hard_linked {
             println!(
-                "{}",
-                format!(" {} ({})", path, human_size(modified.new.size)).bright_yellow()
+                " {} {}",
+                path.to_string().bright_green(),
+                format!("=> {}", linked.target).bright_yellow()
             );
         }
 
@@ -227,11 +1289,6 @@ fn inner_main() -> Result<()> {
     if !cat.type_changed.is_empty() {
         info!("Type changed:");
 
-        let type_letter = |m: DriverItemMetadata| match m {
-            DriverItemMetadata::Directory => "D",
-            DriverItemMetadata::File(_) => "F",
-        };
-
         for (path, type_changed) in &cat.type_changed {
             let message = format!(
                 " {}{} ({} => {})",
@@ -257,81 +1314,185 @@ fn inner_main() -> Result<()> {
                 }
                 DriverItemMetadata::File(m) => info!(
                     " {} {}",
-                    path.bright_red(),
-                    format!("({})", human_size(m.size)).bright_yellow()
+                    path.to_string().bright_red(),
+                    format!("({})", file_size(&m, size_mode)).bright_yellow()
+                ),
+                DriverItemMetadata::Special(m) => info!(
+                    " {} {}",
+                    path.to_string().bright_red(),
+                    format!("({})", m.name()).bright_yellow()
                 ),
             }
         }
 
         info!("");
     }
+}
 
-    info!(
-        "Differences computed in {}.",
-        format!("{}s", started.elapsed().as_secs()).bright_magenta()
-    );
+fn print_summary(cat: &CategorizedDiff, depth: usize, size_mode: SizeMode) {
+    let dirs = cat.summarize(depth, size_mode);
 
-    let transfer_count = cat.added.len() + cat.modified.len() + cat.type_changed.len();
-    let delete_count = cat.type_changed.len() + cat.deleted.len();
-    let transfer_size = cat
-        .added
+    // The root contains every change
+    let total_size = dirs
         .iter()
-        .fold(0, |acc, (_, i)| acc + i.new.size().unwrap_or(0))
-        + cat.modified.iter().fold(0, |acc, (_, i)| acc + i.new.size)
-        + cat
-            .type_changed
-            .iter()
-            .fold(0, |acc, (_, i)| acc + i.new.size().unwrap_or(0));
+        .find(|dir| dir.path.as_bytes().is_empty())
+        .map_or(0, |dir| dir.total_size());
 
-    info!(
-        "Found a total of {} items to transfer and {} to delete for a total of {}.",
-        transfer_count.to_string().bright_green(),
-        delete_count.to_string().bright_red(),
-        human_size(transfer_size).bright_yellow()
-    );
+    info!("Changes by directory:");
 
-    Ok(())
+    for dir in &dirs {
+        let share = if total_size > 0 {
+            dir.total_size() as f64 * 100.0 / total_size as f64
+        } else {
+            0.0
+        };
+
+        let dir_name = if dir.path.as_bytes().is_empty() {
+            "./".to_string()
+        } else {
+            format!("{}/", dir.path)
+        };
+
+        let mut counters = vec![];
+
+        for (counter, name) in [
+            (dir.added, "added"),
+            (dir.modified, "modified"),
+            (dir.type_changed, "type changed"),
+            (dir.deleted, "deleted"),
+        ] {
+            if counter.count > 0 {
+                counters.push(format!(
+                    "{} {} ({})",
+                    counter.count,
+                    name,
+                    human_size(counter.size)
+                ));
+            }
+        }
+
+        for (counter, name) in [
+            (dir.metadata_changed, "metadata changed"),
+            (dir.hard_linked, "hard linked"),
+        ] {
+            if counter.count > 0 {
+                counters.push(format!("{} {}", counter.count, name));
+            }
+        }
+
+        println!(
+            " {} {} {}",
+            format!("{:>6.2}%", share).bright_magenta(),
+            dir_name.bright_yellow(),
+            counters.join(", ")
+        );
+    }
+
+    println!();
 }
 
-pub fn items_spinner() -> (OnItemHandler, OnItemHandler) {
-    let started = Instant::now();
+fn print_tree(cat: &CategorizedDiff, size_mode: SizeMode) {
+    let tree = cat.to_tree(size_mode);
 
-    let started_1 = Arc::new(started);
-    let started_2 = Arc::clone(&started_1);
+    info!("Changes:");
+    println!(".");
+    print_tree_children(&tree.root, "", size_mode);
+    println!();
+}
 
-    let src_counter_1 = Arc::new(AtomicU64::new(0));
-    let src_counter_2 = Arc::clone(&src_counter_1);
+fn print_tree_children(node: &DiffTreeNode, prefix: &str, size_mode: SizeMode) {
+    let count = node.children.len();
 
-    let dest_counter_1 = Arc::new(AtomicU64::new(0));
-    let dest_counter_2 = Arc::clone(&dest_counter_1);
+    for (i, child) in node.children.values().enumerate() {
+        let last = i + 1 == count;
 
-    fn update(src: u64, dest: u64, started: Instant) {
-        print!(
-            "\rSource: found {src} items | Destination: found {dest} items | Searching for {}s...",
-            started.elapsed().as_secs()
-        );
+        let is_dir = match child.change {
+            Some(change) => change.metadata().is_dir(),
+            None => true,
+        };
 
-        stdout().flush().unwrap();
-    }
+        let name = format!("{}{}", child.name, if is_dir { "/" } else { "" });
 
-    update(0, 0, started);
+        let name = match child.change {
+            None => name.normal(),
+            Some(DiffTreeChange::Added(_)) => name.bright_green(),
+            Some(DiffTreeChange::Modified(_)) => name.bright_yellow(),
+            Some(DiffTreeChange::TypeChanged { .. })
+            | Some(DiffTreeChange::MetadataChanged(_))
+            | Some(DiffTreeChange::HardLinked(_)) => name.bright_yellow(),
+            Some(DiffTreeChange::Deleted(_)) => name.bright_red(),
+        };
 
-    (
-        Box::new(move |_| {
-            let updated = src_counter_1.load(Ordering::Acquire) + 1;
-            src_counter_1.store(updated, Ordering::Release);
+        let mut details = vec![];
 
-            if updated % 100 == 0 {
-                update(updated, dest_counter_1.load(Ordering::Acquire), *started_1);
+        match child.change {
+            Some(DiffTreeChange::TypeChanged { prev, new }) => {
+                details.push(format!("{} => {}", type_letter(prev), type_letter(new)))
             }
-        }),
-        Box::new(move |_| {
-            let updated = dest_counter_2.load(Ordering::Acquire) + 1;
-            dest_counter_2.store(updated, Ordering::Release);
+            Some(DiffTreeChange::MetadataChanged(_)) => details.push("metadata".to_string()),
+            Some(DiffTreeChange::HardLinked(_)) => details.push("hard link".to_string()),
+            Some(change) => match change.metadata() {
+                DriverItemMetadata::Directory => {}
+                DriverItemMetadata::File(m) => details.push(file_size(&m, size_mode)),
+                DriverItemMetadata::Special(m) => details.push(m.name().to_string()),
+            },
+            None => {}
+        }
+
+        if child.collapsed.count > 0 {
+            details.push(format!(
+                "{} items, {}",
+                child.collapsed.count,
+                human_size(child.collapsed.size)
+            ));
+        }
+
+        let details = if details.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", details.join(", "))
+        };
+
+        println!(
+            "{}{}{}{}",
+            prefix,
+            if last { "└── " } else { "├── " },
+            name,
+            details.bright_yellow()
+        );
 
-            if updated % 100 == 0 {
-                update(src_counter_2.load(Ordering::Acquire), updated, *started_2);
+        print_tree_children(
+            child,
+            &format!("{}{}", prefix, if last { "    " } else { "│   " }),
+            size_mode,
+        );
+    }
+}
+
+/// Print the library's events: progress is delegated to the progress display, the diff's phases are logged
+pub(super) fn event_printer(progress: Arc<Progress>) -> impl Fn(&Event) + Send + Sync + 'static {
+    move |event| match event {
+        Event::ScanStarted { .. }
+        | Event::ScanProgress { .. }
+        | Event::ScanFinished { .. }
+        | Event::TransferStarted { .. }
+        | Event::TransferProgress { .. }
+        | Event::TransferFinished { .. } => progress.handle(event),
+
+        Event::DiffPhase(phase) => info!(
+            "> {}",
+            match phase {
+                DiffPhase::Added => "Building list of new items...",
+                DiffPhase::Deleted => "Building list of deleted items...",
+                DiffPhase::Modified => "Building list of modified items...",
+                DiffPhase::HardLinks => "Resolving hard links...",
+                DiffPhase::Checksums => "Comparing checksums of modified items...",
             }
-        }),
-    )
+        ),
+
+        Event::Warning(message) => warn!("Warning: {}", message),
+
+        // Errors are returned as well, so they are displayed by the caller
+        Event::Error(_) => {}
+    }
 }
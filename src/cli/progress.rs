@@ -0,0 +1,272 @@
+use std::{
+    collections::BTreeMap,
+    io::{stderr, stdout, IsTerminal, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use differ_backup::{
+    drivers::ItemPath,
+    events::{Event, Side},
+};
+
+use super::{
+    format::{format_duration, human_size},
+    logging::is_stdout_reserved,
+};
+
+/// Minimum delay between two redraws on a terminal
+const TTY_REFRESH: Duration = Duration::from_millis(100);
+
+/// Delay between two log lines when the output is not a terminal (e.g. cron logs)
+const LOG_REFRESH: Duration = Duration::from_secs(10);
+
+/// Maximum number of in-flight files to display
+const MAX_CURRENT_FILES: usize = 8;
+
+/// Progress display for snapshots and transfers
+///
+/// Redrawn in place on a terminal, printed as periodic log lines otherwise
+pub struct Progress {
+    tty: bool,
+    state: Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    started: Instant,
+    last_render: Option<Instant>,
+    rendered_lines: usize,
+
+    source_items: usize,
+    dest_items: usize,
+
+    planned_files: usize,
+    planned_bytes: u64,
+    transfers_started: Option<Instant>,
+    done_files: usize,
+    done_bytes: u64,
+
+    /// Files being transferred, with the number of bytes transferred so far and their size
+    current: BTreeMap<ItemPath, (u64, u64)>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        let tty = if is_stdout_reserved() {
+            stderr().is_terminal()
+        } else {
+            stdout().is_terminal()
+        };
+
+        Self {
+            tty,
+            state: Mutex::new(ProgressState {
+                started: Instant::now(),
+                last_render: None,
+                rendered_lines: 0,
+                source_items: 0,
+                dest_items: 0,
+                planned_files: 0,
+                planned_bytes: 0,
+                transfers_started: None,
+                done_files: 0,
+                done_bytes: 0,
+                current: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Set the number of files and bytes to transfer, used to compute the ETA
+    pub fn set_planned(&self, files: usize, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.planned_files = files;
+        state.planned_bytes = bytes;
+    }
+
+    /// Update the display with an event (events unrelated to progress are ignored)
+    pub fn handle(&self, event: &Event) {
+        // Avoid contention when drivers find lots of items in parallel
+        if let Event::ScanProgress { items, .. } = event {
+            if !items.is_multiple_of(100) {
+                return;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        match event {
+            Event::ScanStarted { .. } => {}
+
+            Event::ScanProgress { side, items } | Event::ScanFinished { side, items } => match side
+            {
+                Side::Source => state.source_items = *items,
+                Side::Destination => state.dest_items = *items,
+            },
+
+            Event::TransferStarted { path, size } => {
+                state.transfers_started.get_or_insert_with(Instant::now);
+                state.current.insert(path.clone(), (0, *size));
+            }
+
+            Event::TransferProgress {
+                path,
+                transferred,
+                total,
+            } => {
+                state.current.insert(path.clone(), (*transferred, *total));
+            }
+
+            Event::TransferFinished { path } => {
+                if let Some((_, size)) = state.current.remove(path) {
+                    state.done_files += 1;
+                    state.done_bytes += size;
+                }
+            }
+
+            Event::DiffPhase(_) | Event::Warning(_) | Event::Error(_) => return,
+        }
+
+        self.render(&mut state);
+    }
+
+    /// Remove the progress display from a terminal, to print other messages after it
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if self.tty {
+            write_output(&clear_lines(state.rendered_lines));
+            state.rendered_lines = 0;
+        }
+
+        state.last_render = None;
+    }
+
+    fn render(&self, state: &mut ProgressState) {
+        let refresh = if self.tty { TTY_REFRESH } else { LOG_REFRESH };
+
+        match state.last_render {
+            Some(last_render) if last_render.elapsed() < refresh => return,
+            // Don't log anything for operations which are quick enough
+            None if !self.tty && state.started.elapsed() < refresh => return,
+            _ => {}
+        }
+
+        state.last_render = Some(Instant::now());
+
+        let lines = state.lines(self.tty);
+
+        if self.tty {
+            let mut output = clear_lines(state.rendered_lines);
+
+            for (i, line) in lines.iter().enumerate() {
+                if i > 0 {
+                    output.push('\n');
+                }
+
+                output.push_str(&line.bright_blue().to_string());
+            }
+
+            state.rendered_lines = lines.len();
+            write_output(&output);
+        } else {
+            write_output(&format!("{}\n", lines.join(" | ")));
+        }
+    }
+}
+
+impl ProgressState {
+    fn lines(&self, tty: bool) -> Vec<String> {
+        let Some(transfers_started) = self.transfers_started else {
+            return vec![format!(
+                "Source: found {} items | Destination: found {} items | Searching for {}...",
+                self.source_items,
+                self.dest_items,
+                format_duration(self.started.elapsed())
+            )];
+        };
+
+        let in_flight = self
+            .current
+            .values()
+            .map(|(transferred, _)| transferred)
+            .sum::<u64>();
+
+        let done_bytes = self.done_bytes + in_flight;
+        let elapsed = transfers_started.elapsed().as_secs_f64();
+
+        let throughput = if elapsed > 0.0 {
+            done_bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let eta = if throughput > 0.0 && self.planned_bytes > done_bytes {
+            format_duration(Duration::from_secs_f64(
+                (self.planned_bytes - done_bytes) as f64 / throughput,
+            ))
+        } else {
+            "-".to_string()
+        };
+
+        let percent = if self.planned_bytes > 0 {
+            done_bytes as f64 * 100.0 / self.planned_bytes as f64
+        } else {
+            100.0
+        };
+
+        let mut lines = vec![format!(
+            "Transferred {}/{} files, {}/{} ({:.1}%) | {}/s | ETA {}",
+            self.done_files,
+            self.planned_files,
+            human_size(done_bytes),
+            human_size(self.planned_bytes),
+            percent,
+            human_size(throughput as u64),
+            eta
+        )];
+
+        // Log lines only contain the totals
+        if tty {
+            for (path, (transferred, total)) in self.current.iter().take(MAX_CURRENT_FILES) {
+                let percent = if *total > 0 {
+                    *transferred as f64 * 100.0 / *total as f64
+                } else {
+                    100.0
+                };
+
+                lines.push(format!("  {} ({:.1}%)", path, percent));
+            }
+
+            if self.current.len() > MAX_CURRENT_FILES {
+                lines.push(format!(
+                    "  ... and {} more",
+                    self.current.len() - MAX_CURRENT_FILES
+                ));
+            }
+        }
+
+        lines
+    }
+}
+
+/// Move the cursor back to the start of the previously rendered lines and clear them
+fn clear_lines(rendered_lines: usize) -> String {
+    match rendered_lines {
+        0 => String::new(),
+        1 => "\r\x1b[J".to_string(),
+        lines => format!("\x1b[{}A\r\x1b[J", lines - 1),
+    }
+}
+
+fn write_output(text: &str) {
+    if is_stdout_reserved() {
+        let mut stderr = stderr().lock();
+        stderr.write_all(text.as_bytes()).unwrap();
+        stderr.flush().unwrap();
+    } else {
+        let mut stdout = stdout().lock();
+        stdout.write_all(text.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+    }
+}
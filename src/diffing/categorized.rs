@@ -1,25 +1,57 @@
+use std::collections::HashMap;
+
+use crate::drivers::{DriverItemMetadata, ItemPath, SizeMode};
+
 use super::{
-    Diff, DiffItemAdded, DiffItemDeleted, DiffItemModified, DiffItemTypeChanged, DiffType,
+    Diff, DiffItemAdded, DiffItemDeleted, DiffItemHardLinked, DiffItemMetadataChanged,
+    DiffItemModified, DiffItemTypeChanged, DiffType,
 };
 
+/// Totals of the changes to apply to the destination
+#[derive(Debug, Clone, Copy)]
+pub struct DiffTotals {
+    /// Number of items to transfer
+    pub transfer_count: usize,
+
+    /// Number of items to delete (including items replaced by another type)
+    pub delete_count: usize,
+
+    /// Total size of the items to transfer
+    pub transfer_size: u64,
+}
+
 pub struct CategorizedDiff {
-    pub added: Vec<(String, DiffItemAdded)>,
-    pub modified: Vec<(String, DiffItemModified)>,
-    pub type_changed: Vec<(String, DiffItemTypeChanged)>,
-    pub deleted: Vec<(String, DiffItemDeleted)>,
+    pub added: Vec<(ItemPath, DiffItemAdded)>,
+    pub modified: Vec<(ItemPath, DiffItemModified)>,
+    pub metadata_changed: Vec<(ItemPath, DiffItemMetadataChanged)>,
+    pub hard_linked: Vec<(ItemPath, DiffItemHardLinked)>,
+    pub type_changed: Vec<(ItemPath, DiffItemTypeChanged)>,
+    pub deleted: Vec<(ItemPath, DiffItemDeleted)>,
+
+    /// Paths of the items spelled differently in the destination (see [`super::DiffItem::dest_path`])
+    dest_paths: HashMap<ItemPath, ItemPath>,
 }
 
 impl CategorizedDiff {
     pub fn new(diff: Diff) -> Self {
         let mut added = vec![];
         let mut modified = vec![];
+        let mut metadata_changed = vec![];
+        let mut hard_linked = vec![];
         let mut type_changed = vec![];
         let mut deleted = vec![];
+        let mut dest_paths = HashMap::new();
 
         for item in diff.into_items() {
+            if let Some(dest_path) = item.dest_path {
+                dest_paths.insert(item.path.clone(), dest_path);
+            }
+
             match item.status {
                 DiffType::Added(i) => added.push((item.path, i)),
                 DiffType::Modified(i) => modified.push((item.path, i)),
+                DiffType::MetadataChanged(i) => metadata_changed.push((item.path, i)),
+                DiffType::HardLinked(i) => hard_linked.push((item.path, i)),
                 DiffType::TypeChanged(i) => type_changed.push((item.path, i)),
                 DiffType::Deleted(i) => deleted.push((item.path, i)),
             }
@@ -28,8 +60,59 @@ impl CategorizedDiff {
         Self {
             added,
             modified,
+            metadata_changed,
+            hard_linked,
             type_changed,
             deleted,
+            dest_paths,
+        }
+    }
+
+    /// Path to use for operations on the destination
+    pub fn dest_path<'a>(&'a self, path: &'a ItemPath) -> &'a ItemPath {
+        self.dest_paths.get(path).unwrap_or(path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.metadata_changed.is_empty()
+            && self.hard_linked.is_empty()
+            && self.type_changed.is_empty()
+            && self.deleted.is_empty()
+    }
+
+    pub fn totals(&self, size_mode: SizeMode) -> DiffTotals {
+        let transfer_count = self.added.len() + self.modified.len() + self.type_changed.len();
+
+        let delete_count = self.type_changed.len()
+            + self.deleted.len()
+            + self
+                .hard_linked
+                .iter()
+                .filter(|(_, i)| matches!(i.prev, Some(DriverItemMetadata::Directory)))
+                .count();
+
+        let transfer_size = self
+            .added
+            .iter()
+            .map(|(_, i)| i.new.size_with(size_mode).unwrap_or(0))
+            .chain(
+                self.modified
+                    .iter()
+                    .map(|(_, i)| i.new.size_with(size_mode)),
+            )
+            .chain(
+                self.type_changed
+                    .iter()
+                    .map(|(_, i)| i.new.size_with(size_mode).unwrap_or(0)),
+            )
+            .sum();
+
+        DiffTotals {
+            transfer_count,
+            delete_count,
+            transfer_size,
         }
     }
 }
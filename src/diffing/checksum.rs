@@ -0,0 +1,48 @@
+use anyhow::Result;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use super::{Diff, DiffItem, DiffType};
+use crate::{
+    drivers::Driver,
+    events::{DiffPhase, Event, Events},
+};
+
+/// Remove modified files whose size didn't change and whose content is identical
+/// on both sides (only their modification date differs)
+///
+/// Files whose permissions, ownership or extended attributes changed are kept as
+/// [`DiffType::MetadataChanged`] items instead.
+pub fn filter_identical_checksums(
+    diff: Diff,
+    source_driver: &(dyn Driver + Sync),
+    source_dir: &str,
+    dest_driver: &(dyn Driver + Sync),
+    dest_dir: &str,
+    events: &Events,
+) -> Result<Diff> {
+    events.emit(Event::DiffPhase(DiffPhase::Checksums));
+
+    let items = diff
+        .into_items()
+        .into_par_iter()
+        .map(|item| {
+            if let DiffType::Modified(modified) = &item.status {
+                if modified.prev.size == modified.new.size
+                    && source_driver.checksum(source_dir, &item.path)?
+                        == dest_driver.checksum(dest_dir, item.dest_path())?
+                {
+                    return Ok(modified.metadata_changed.clone().map(|changed| DiffItem {
+                        path: item.path.clone(),
+                        status: DiffType::MetadataChanged(changed),
+                        dest_path: item.dest_path.clone(),
+                    }));
+                }
+            }
+
+            Ok(Some(item))
+        })
+        .filter_map(|r| r.transpose())
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Diff::new(items))
+}
@@ -1,13 +1,19 @@
+use super::{hard_links::resolve_hard_links, UnicodeNormalization};
 use crate::{
-    drivers::{DriverFileMetadata, DriverItem, DriverItemMetadata, Snapshot},
-    info,
+    drivers::{
+        DriverFileMetadata, DriverItem, DriverItemMetadata, DriverItemPermissions, ItemPath,
+        Snapshot,
+    },
+    events::{DiffPhase, Event, Events},
 };
 
 use std::{
-    cmp::Ordering,
-    collections::{HashMap, HashSet},
+    borrow::Cow, cmp::Ordering, collections::HashMap, convert::Infallible, str::FromStr,
+    time::Duration,
 };
 
+use anyhow::{bail, Error};
+
 pub struct Diff(Vec<DiffItem>);
 
 impl Diff {
@@ -30,8 +36,19 @@ impl Diff {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct DiffItem {
-    pub path: String,
+    pub path: ItemPath,
     pub status: DiffType,
+
+    /// Path of the item in the destination, when it is spelled differently than in the source
+    /// (see [`DiffOptions::path_key`])
+    pub dest_path: Option<ItemPath>,
+}
+
+impl DiffItem {
+    /// Path to use for operations on the destination
+    pub fn dest_path(&self) -> &ItemPath {
+        self.dest_path.as_ref().unwrap_or(&self.path)
+    }
 }
 
 impl PartialOrd for DiffItem {
@@ -49,23 +66,107 @@ impl Ord for DiffItem {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DiffType {
     Added(DiffItemAdded),
     Modified(DiffItemModified),
-    TypeChanged(DiffItemTypeChanged), // File => Dir / Dir => File
+    MetadataChanged(DiffItemMetadataChanged), // Permissions, ownership or extended attributes only
+    HardLinked(DiffItemHardLinked),           // Hard link to another file of the source
+    TypeChanged(DiffItemTypeChanged),         // File => Dir / Dir => File
     Deleted(DiffItemDeleted),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+impl DiffType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Added(_) => "added",
+            Self::Modified(_) => "modified",
+            Self::MetadataChanged(_) => "metadata-changed",
+            Self::HardLinked(_) => "hard-linked",
+            Self::TypeChanged(_) => "type-changed",
+            Self::Deleted(_) => "deleted",
+        }
+    }
+
+    /// Metadata of the item in the destination, before the changes are applied
+    pub fn prev_metadata(&self) -> Option<DriverItemMetadata> {
+        match self {
+            Self::Added(_) => None,
+            Self::Modified(i) => Some(DriverItemMetadata::File(i.prev)),
+            Self::MetadataChanged(i) => Some(i.item),
+            Self::HardLinked(i) => i.prev,
+            Self::TypeChanged(i) => Some(i.prev),
+            Self::Deleted(i) => Some(i.prev),
+        }
+    }
+
+    /// Metadata of the item in the destination, after the changes are applied
+    pub fn new_metadata(&self) -> Option<DriverItemMetadata> {
+        match self {
+            Self::Added(i) => Some(i.new),
+            Self::Modified(i) => Some(DriverItemMetadata::File(i.new)),
+            Self::MetadataChanged(i) => Some(i.item),
+            Self::HardLinked(i) => Some(DriverItemMetadata::File(i.new)),
+            Self::TypeChanged(i) => Some(i.new),
+            Self::Deleted(_) => None,
+        }
+    }
+
+    /// Check if two changes made to the same item lead to the same result, e.g. when comparing
+    /// the changes made to two replicas of the same directory
+    pub fn has_same_result(&self, other: &DiffType, options: &DiffOptions) -> bool {
+        if let (Self::MetadataChanged(a), Self::MetadataChanged(b)) = (self, other) {
+            return options.is_same_permissions(&a.new, &b.new)
+                && !a.xattrs_changed
+                && !b.xattrs_changed;
+        }
+
+        match (self.new_metadata(), other.new_metadata()) {
+            (None, None) => true,
+            (Some(DriverItemMetadata::Directory), Some(DriverItemMetadata::Directory)) => true,
+            (Some(DriverItemMetadata::File(a)), Some(DriverItemMetadata::File(b))) => {
+                options.is_same_file(&a, &b)
+            }
+            (Some(DriverItemMetadata::Special(a)), Some(DriverItemMetadata::Special(b))) => {
+                a.is_same(&b)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DiffItemAdded {
     pub new: DriverItemMetadata,
+    pub permissions: DriverItemPermissions,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DiffItemModified {
     pub prev: DriverFileMetadata,
     pub new: DriverFileMetadata,
+
+    /// Permissions, ownership or extended attributes which changed along with the content
+    pub metadata_changed: Option<DiffItemMetadataChanged>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiffItemMetadataChanged {
+    pub item: DriverItemMetadata,
+    pub prev: DriverItemPermissions,
+    pub new: DriverItemPermissions,
+    pub xattrs_changed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiffItemHardLinked {
+    /// Path of the file this item is linked to
+    pub target: ItemPath,
+
+    /// Path of the target in the destination, when it is spelled differently than in the source
+    pub dest_target: Option<ItemPath>,
+    pub prev: Option<DriverItemMetadata>,
+    pub new: DriverFileMetadata,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -79,93 +180,544 @@ pub struct DiffItemDeleted {
     pub prev: DriverItemMetadata,
 }
 
-pub fn build_diff(source: Snapshot, dest_dir: Snapshot) -> Diff {
-    let source_items = build_item_names_hashmap(&source);
-    let backed_up_items = build_item_names_hashmap(&dest_dir);
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions {
+    /// How files present on both sides are compared
+    pub compare: CompareMode,
+
+    /// Maximum difference between modification dates for them to be considered equal
+    pub mtime_tolerance: Duration,
+
+    /// Offset in seconds added to the destination's modification dates before comparing them
+    pub mtime_offset: i64,
+
+    /// Compare permission bits
+    pub compare_permissions: bool,
+
+    /// Compare owner and group IDs
+    pub compare_ownership: bool,
+
+    /// Compare extended attributes (including POSIX ACLs)
+    pub compare_xattrs: bool,
+
+    /// Detect hard links in the source and report them as links instead of separate files
+    pub hard_links: bool,
+
+    /// Ignore named pipes, sockets and device nodes on both sides
+    pub skip_special: bool,
+
+    /// Unicode normalization applied to paths before comparing them
+    pub normalization: Option<UnicodeNormalization>,
+
+    /// Compare paths case-insensitively
+    pub case_insensitive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompareMode {
+    /// Compare both modification date and size
+    #[default]
+    MtimeAndSize,
+
+    /// Only compare sizes
+    SizeOnly,
+
+    /// Compare modification date and size, but ignore modification dates
+    /// if checksums match (see [`super::filter_identical_checksums`])
+    Checksum,
+}
+
+impl FromStr for CompareMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mtime-size" => Ok(Self::MtimeAndSize),
+            "size-only" => Ok(Self::SizeOnly),
+            "checksum" => Ok(Self::Checksum),
+            _ => bail!(
+                "Unknown comparison mode '{}' (expected 'mtime-size', 'size-only' or 'checksum')",
+                s
+            ),
+        }
+    }
+}
+
+impl DiffOptions {
+    pub fn is_same_file(&self, source: &DriverFileMetadata, dest: &DriverFileMetadata) -> bool {
+        if source.size != dest.size {
+            return false;
+        }
+
+        match self.compare {
+            CompareMode::SizeOnly => true,
+            CompareMode::MtimeAndSize | CompareMode::Checksum => self.is_same_mtime(source, dest),
+        }
+    }
+
+    pub fn is_same_mtime(&self, source: &DriverFileMetadata, dest: &DriverFileMetadata) -> bool {
+        // Only compare sub-second precision when both sides provide it
+        let (source_nanos, dest_nanos) =
+            match (source.modification_date_nanos, dest.modification_date_nanos) {
+                (Some(source_nanos), Some(dest_nanos)) => (source_nanos, dest_nanos),
+                _ => (0, 0),
+            };
+
+        let source =
+            i128::from(source.modification_date) * 1_000_000_000 + i128::from(source_nanos);
+        let dest = (i128::from(dest.modification_date) + i128::from(self.mtime_offset))
+            * 1_000_000_000
+            + i128::from(dest_nanos);
+
+        (source - dest).unsigned_abs() <= self.mtime_tolerance.as_nanos()
+    }
+}
+
+impl DiffOptions {
+    pub fn is_same_permissions(
+        &self,
+        source: &DriverItemPermissions,
+        dest: &DriverItemPermissions,
+    ) -> bool {
+        // Values missing on either side can't be compared
+        fn same<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+
+        (!self.compare_permissions || same(source.mode, dest.mode))
+            && (!self.compare_ownership
+                || (same(source.uid, dest.uid) && same(source.gid, dest.gid)))
+    }
+}
+
+/// Constant offset between the source and destination's modification dates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtimeOffset {
+    /// Offset in seconds (source minus destination)
+    pub offset: i64,
 
-    let source_items_paths: HashSet<_> = source_items.keys().collect();
-    let backed_up_items_paths: HashSet<_> = backed_up_items.keys().collect();
+    /// Number of modified files exhibiting this offset
+    pub matching: usize,
 
-    let mut diff = Vec::with_capacity(source_items.len());
+    /// Total number of modified files with an unchanged size
+    pub candidates: usize,
+}
+
+impl Diff {
+    /// Detect if a large fraction of the modified files only differ by a constant whole-hour
+    /// offset, which usually happens with FAT-formatted disks storing local time after a DST change
+    pub fn detect_mtime_offset(&self, options: &DiffOptions) -> Option<MtimeOffset> {
+        let mut detector = MtimeOffsetDetector::default();
 
-    info!("> Building list of new items...");
+        for item in &self.0 {
+            detector.add(item, options);
+        }
 
-    diff.extend(
-        source_items_paths
-            .difference(&backed_up_items_paths)
-            .map(|item| DiffItem {
-                path: String::clone(item),
-                status: DiffType::Added(DiffItemAdded {
-                    new: source_items.get(*item).unwrap().metadata,
-                }),
+        detector.result()
+    }
+}
+
+/// Incremental version of [`Diff::detect_mtime_offset`], for diffs that are never fully built
+#[derive(Default)]
+pub struct MtimeOffsetDetector {
+    offsets: HashMap<i64, usize>,
+    candidates: usize,
+}
+
+impl MtimeOffsetDetector {
+    pub fn add(&mut self, item: &DiffItem, options: &DiffOptions) {
+        const MAX_HOURS: i128 = 14;
+
+        // FAT only stores modification dates with a 2 seconds precision
+        const MIN_SLACK: u64 = 2;
+
+        let DiffType::Modified(modified) = &item.status else {
+            return;
+        };
+
+        if modified.prev.size != modified.new.size {
+            return;
+        }
+
+        self.candidates += 1;
+
+        let tolerance = i128::from(options.mtime_tolerance.as_secs().max(MIN_SLACK));
+
+        let delta = i128::from(modified.new.modification_date)
+            - (i128::from(modified.prev.modification_date) + i128::from(options.mtime_offset));
+
+        // Round to the nearest hour
+        let hours = (delta + delta.signum() * 1800) / 3600;
+
+        if hours != 0 && hours.abs() <= MAX_HOURS && (delta - hours * 3600).abs() <= tolerance {
+            // Can't overflow as it is at most `MAX_HOURS` hours
+            *self.offsets.entry((hours * 3600) as i64).or_default() += 1;
+        }
+    }
+
+    pub fn result(self) -> Option<MtimeOffset> {
+        const MIN_CANDIDATES: usize = 10;
+
+        let candidates = self.candidates;
+        let (offset, matching) = self.offsets.into_iter().max_by_key(|(_, count)| *count)?;
+
+        if candidates < MIN_CANDIDATES || matching * 2 < candidates {
+            return None;
+        }
+
+        Some(MtimeOffset {
+            offset,
+            matching,
+            candidates,
+        })
+    }
+}
+
+/// Compare a source snapshot to a destination one
+///
+/// Every item of the destination missing from the source is reported as deleted. When several
+/// directories are mapped into parts of the destination (see [`MergedDriver`]), the destination
+/// snapshot must first be restricted to them with [`Snapshot::retain_mapped`] so the rest of it
+/// isn't deleted.
+///
+/// [`MergedDriver`]: crate::drivers::merged::MergedDriver
+pub fn build_diff(
+    source: &Snapshot,
+    dest_dir: &Snapshot,
+    options: &DiffOptions,
+    events: &Events,
+) -> Diff {
+    let mut diff = Vec::with_capacity(source.items.len());
+
+    let Ok(()) = stream_diff(source, dest_dir, options, events, |item| {
+        diff.push(item);
+        Ok::<_, Infallible>(())
+    });
+
+    if options.hard_links {
+        events.emit(Event::DiffPhase(DiffPhase::HardLinks));
+
+        resolve_hard_links(&mut diff, source, dest_dir, options);
+    }
+
+    Diff::new(diff)
+}
+
+/// Compare a source snapshot to a destination one like [`build_diff`], passing each difference
+/// to `on_item` as soon as it is found so they don't have to be held in memory all at once
+///
+/// Differences are found by category (added, deleted, then the others), each in path order.
+/// Hard links are never resolved, as it requires the whole diff. The first error returned by
+/// `on_item` stops the comparison.
+pub fn stream_diff<E>(
+    source: &Snapshot,
+    dest_dir: &Snapshot,
+    options: &DiffOptions,
+    events: &Events,
+    mut on_item: impl FnMut(DiffItem) -> Result<(), E>,
+) -> Result<(), E> {
+    let source_items = build_item_names_hashmap(source, options);
+    let backed_up_items = build_item_names_hashmap(dest_dir, options);
+
+    let source_keys = sorted_keys(&source_items);
+
+    events.emit(Event::DiffPhase(DiffPhase::Added));
+
+    for key in source_keys
+        .iter()
+        .filter(|key| !backed_up_items.contains_key(**key))
+    {
+        let source_item = source_items[*key];
+
+        on_item(DiffItem {
+            path: source_item.path.clone(),
+            status: DiffType::Added(DiffItemAdded {
+                new: source_item.metadata,
+                permissions: source_item.permissions.clone(),
             }),
-    );
-
-    info!("> Building list of deleted items...");
-
-    diff.extend(
-        backed_up_items_paths
-            .difference(&source_items_paths)
-            .map(|item| DiffItem {
-                path: String::clone(item),
-                status: DiffType::Deleted(DiffItemDeleted {
-                    prev: backed_up_items.get(*item).unwrap().metadata,
-                }),
+            dest_path: None,
+        })?;
+    }
+
+    events.emit(Event::DiffPhase(DiffPhase::Deleted));
+
+    for key in sorted_keys(&backed_up_items)
+        .iter()
+        .filter(|key| !source_items.contains_key(**key))
+    {
+        let backed_up_item = backed_up_items[*key];
+
+        on_item(DiffItem {
+            path: backed_up_item.path.clone(),
+            status: DiffType::Deleted(DiffItemDeleted {
+                prev: backed_up_item.metadata,
             }),
-    );
-
-    info!("> Building list of modified items...");
-
-    diff.extend(
-        source
-            .items
-            .iter()
-            .filter(|item| backed_up_items_paths.contains(&&item.path))
-            .filter_map(|source_item| {
-                let backed_up_item = backed_up_items.get(&source_item.path).unwrap();
-
-                match (source_item.metadata, backed_up_item.metadata) {
-                    // Both directories = no change
-                    (DriverItemMetadata::Directory, DriverItemMetadata::Directory) => None,
-                    // Source item is directory and backed up item is file or the opposite = type changed
-                    (DriverItemMetadata::Directory, DriverItemMetadata::File { .. })
-                    | (DriverItemMetadata::File { .. }, DriverItemMetadata::Directory) => {
-                        Some(DiffItem {
-                            path: source_item.path.clone(),
-                            status: DiffType::TypeChanged(DiffItemTypeChanged {
-                                prev: backed_up_item.metadata,
-                                new: source_item.metadata,
-                            }),
-                        })
-                    }
-                    // Otherwise, compare their metadata to see if something changed
-                    (
-                        DriverItemMetadata::File(source_data),
-                        DriverItemMetadata::File(backed_up_data),
-                    ) => {
-                        if source_data == backed_up_data {
-                            None
-                        } else {
-                            Some(DiffItem {
-                                path: source_item.path.clone(),
-                                status: DiffType::Modified(DiffItemModified {
-                                    prev: backed_up_data,
-                                    new: source_data,
-                                }),
-                            })
-                        }
-                    }
-                }
+            dest_path: None,
+        })?;
+    }
+
+    events.emit(Event::DiffPhase(DiffPhase::Modified));
+
+    for key in &source_keys {
+        let Some(backed_up_item) = backed_up_items.get(*key) else {
+            continue;
+        };
+
+        if let Some(item) = compare_items(source_items[*key], backed_up_item, options) {
+            on_item(item)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keys of a [`build_item_names_hashmap`] map, in path order
+fn sorted_keys<'a, 'b>(
+    items: &'a HashMap<Cow<'b, ItemPath>, &DriverItem>,
+) -> Vec<&'a Cow<'b, ItemPath>> {
+    let mut keys = items.keys().collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+/// Compare an item present on both sides
+fn compare_items(
+    source_item: &DriverItem,
+    backed_up_item: &DriverItem,
+    options: &DiffOptions,
+) -> Option<DiffItem> {
+    match (source_item.metadata, backed_up_item.metadata) {
+        // Both directories = only metadata may have changed
+        (DriverItemMetadata::Directory, DriverItemMetadata::Directory) => {
+            metadata_changed_item(source_item, backed_up_item, options)
+        }
+        // Otherwise, compare their metadata to see if something changed
+        (DriverItemMetadata::File(source_data), DriverItemMetadata::File(backed_up_data)) => {
+            if options.is_same_file(&source_data, &backed_up_data) {
+                metadata_changed_item(source_item, backed_up_item, options)
+            } else {
+                Some(DiffItem {
+                    path: source_item.path.clone(),
+                    status: DiffType::Modified(DiffItemModified {
+                        prev: backed_up_data,
+                        new: source_data,
+                        metadata_changed: metadata_changed(source_item, backed_up_item, options),
+                    }),
+                    dest_path: renamed_dest_path(source_item, backed_up_item),
+                })
+            }
+        }
+        // Same kind of special item (and same device ID) = only metadata may have changed
+        (DriverItemMetadata::Special(source_data), DriverItemMetadata::Special(backed_up_data))
+            if source_data.is_same(&backed_up_data) =>
+        {
+            metadata_changed_item(source_item, backed_up_item, options)
+        }
+        // Any other combination = type changed
+        _ => Some(DiffItem {
+            path: source_item.path.clone(),
+            status: DiffType::TypeChanged(DiffItemTypeChanged {
+                prev: backed_up_item.metadata,
+                new: source_item.metadata,
             }),
-    );
+            dest_path: renamed_dest_path(source_item, backed_up_item),
+        }),
+    }
+}
 
-    Diff::new(diff)
+fn metadata_changed_item(
+    source_item: &DriverItem,
+    backed_up_item: &DriverItem,
+    options: &DiffOptions,
+) -> Option<DiffItem> {
+    Some(DiffItem {
+        path: source_item.path.clone(),
+        status: DiffType::MetadataChanged(metadata_changed(source_item, backed_up_item, options)?),
+        dest_path: renamed_dest_path(source_item, backed_up_item),
+    })
 }
 
-fn build_item_names_hashmap(snapshot: &Snapshot) -> HashMap<&String, &DriverItem> {
-    snapshot
-        .items
-        .iter()
-        .map(|item| (&item.path, item))
-        .collect::<HashMap<_, _>>()
+/// Get the destination's path of an item matched with a source item, if spelled differently
+pub(super) fn renamed_dest_path(source: &DriverItem, dest: &DriverItem) -> Option<ItemPath> {
+    (source.path != dest.path).then(|| dest.path.clone())
+}
+
+/// Compare the permissions, ownership and extended attributes of an item
+pub(crate) fn metadata_changed(
+    source_item: &DriverItem,
+    backed_up_item: &DriverItem,
+    options: &DiffOptions,
+) -> Option<DiffItemMetadataChanged> {
+    let permissions_changed =
+        !options.is_same_permissions(&source_item.permissions, &backed_up_item.permissions);
+
+    // Extended attributes can only be compared if both drivers read them
+    let xattrs_changed = options.compare_xattrs
+        && match (&source_item.xattrs, &backed_up_item.xattrs) {
+            (Some(source), Some(backed_up)) => source != backed_up,
+            _ => false,
+        };
+
+    if !permissions_changed && !xattrs_changed {
+        return None;
+    }
+
+    Some(DiffItemMetadataChanged {
+        item: source_item.metadata,
+        prev: backed_up_item.permissions.clone(),
+        new: source_item.permissions.clone(),
+        xattrs_changed,
+    })
+}
+
+fn build_item_names_hashmap<'a>(
+    snapshot: &'a Snapshot,
+    options: &DiffOptions,
+) -> HashMap<Cow<'a, ItemPath>, &'a DriverItem> {
+    let mut items = HashMap::with_capacity(snapshot.items.len());
+
+    for item in &snapshot.items {
+        if options.skip_special && matches!(item.metadata, DriverItemMetadata::Special(_)) {
+            continue;
+        }
+
+        // When names collide (see [`super::find_name_collisions`]), only the lowest one is kept
+        items
+            .entry(options.path_key(&item.path))
+            .and_modify(|existing: &mut &DriverItem| {
+                if item.path < existing.path {
+                    *existing = item;
+                }
+            })
+            .or_insert(item);
+    }
+
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(modification_date: i64, modification_date_nanos: Option<u32>) -> DriverFileMetadata {
+        DriverFileMetadata {
+            modification_date,
+            modification_date_nanos,
+            size: 1,
+            allocated_size: None,
+        }
+    }
+
+    /// Diff of files whose modification dates went from `prev` (destination) to `new` (source)
+    fn modified(dates: impl IntoIterator<Item = (i64, i64)>) -> Diff {
+        Diff::new(
+            dates
+                .into_iter()
+                .enumerate()
+                .map(|(i, (prev, new))| DiffItem {
+                    path: ItemPath::new(format!("file{}", i).into_bytes()),
+                    status: DiffType::Modified(DiffItemModified {
+                        prev: file(prev, None),
+                        new: file(new, None),
+                        metadata_changed: None,
+                    }),
+                    dest_path: None,
+                })
+                .collect(),
+        )
+    }
+
+    fn tolerance(secs: u64) -> DiffOptions {
+        DiffOptions {
+            mtime_tolerance: Duration::from_secs(secs),
+            ..DiffOptions::default()
+        }
+    }
+
+    #[test]
+    fn dst_shifts_are_detected() {
+        let options = tolerance(0);
+        let base = 1_700_000_000;
+
+        for shift in [3600, -3600] {
+            let diff = modified((0..10).map(|i| (base + i, base + i + shift)));
+            let offset = diff.detect_mtime_offset(&options).unwrap();
+
+            assert_eq!(offset.offset, shift);
+            assert_eq!((offset.matching, offset.candidates), (10, 10));
+
+            // Nothing is left once the offset is compensated
+            let compensated = DiffOptions {
+                mtime_offset: shift,
+                ..options
+            };
+
+            assert!(diff.detect_mtime_offset(&compensated).is_none());
+        }
+
+        // Too few files to conclude anything
+        assert!(modified((0..9).map(|i| (base + i, base + i + 3600)))
+            .detect_mtime_offset(&options)
+            .is_none());
+
+        // Shifts which aren't a whole number of hours
+        assert!(modified((0..10).map(|i| (base + i, base + i + 1800)))
+            .detect_mtime_offset(&options)
+            .is_none());
+    }
+
+    #[test]
+    fn fat_odd_seconds_are_tolerated() {
+        // FAT rounds odd seconds to the next even one
+        let base = 1_700_000_001;
+        let diff = modified((0..10).map(|i| (base + 2 * i + 1 + 3600, base + 2 * i)));
+
+        let offset = diff.detect_mtime_offset(&tolerance(0)).unwrap();
+        assert_eq!(offset.offset, -3600);
+
+        let options = DiffOptions {
+            mtime_offset: -3600,
+            ..tolerance(2)
+        };
+
+        assert!(options.is_same_mtime(&file(base, None), &file(base + 1 + 3600, None)));
+        assert!(!options.is_same_mtime(&file(base, None), &file(base + 3 + 3600, None)));
+
+        // Nanoseconds are ignored when the destination doesn't provide them
+        assert!(tolerance(0).is_same_mtime(&file(base, Some(999_999_999)), &file(base, None)));
+        assert!(!tolerance(0).is_same_mtime(&file(base, Some(1)), &file(base, Some(0))));
+    }
+
+    #[test]
+    fn extreme_dates_and_offsets_dont_overflow() {
+        let options = DiffOptions {
+            mtime_offset: i64::MAX,
+            ..tolerance(2)
+        };
+
+        assert!(!options.is_same_mtime(&file(i64::MIN, Some(0)), &file(i64::MAX, Some(0))));
+        assert!(options.is_same_mtime(&file(i64::MAX, None), &file(0, None)));
+
+        let options = DiffOptions {
+            mtime_offset: i64::MIN,
+            ..tolerance(2)
+        };
+
+        assert!(options.is_same_mtime(&file(i64::MIN, None), &file(0, None)));
+
+        // Dates at both ends are far more than a few hours apart
+        let diff = modified((0..10).map(|_| (i64::MIN, i64::MAX)));
+        assert!(diff.detect_mtime_offset(&options).is_none());
+        assert!(diff.detect_mtime_offset(&tolerance(0)).is_none());
+
+        // A whole-hour shift is still found right next to the bounds
+        let diff = modified((0..10).map(|i| (i64::MAX - i, i64::MAX - i - 3600)));
+        assert_eq!(
+            diff.detect_mtime_offset(&tolerance(0)).unwrap().offset,
+            -3600
+        );
+    }
 }
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{diff::renamed_dest_path, DiffItem, DiffItemHardLinked, DiffOptions, DiffType};
+use crate::drivers::{DriverItem, DriverItemMetadata, HardLinkId, Snapshot};
+
+/// Replace the entries of files which are hard links to another file in the source
+///
+/// For each group of hard links, the first path (in lexicographic order) is the group's leader
+/// and is compared as usual. Other members are reported as [`DiffType::HardLinked`] to the leader,
+/// unless they are already linked to it in the destination and the leader is unchanged.
+pub(super) fn resolve_hard_links(
+    diff: &mut Vec<DiffItem>,
+    source: &Snapshot,
+    dest: &Snapshot,
+    options: &DiffOptions,
+) {
+    let mut groups = HashMap::<HardLinkId, Vec<&DriverItem>>::new();
+
+    for item in &source.items {
+        if let (Some(id), DriverItemMetadata::File(_)) = (item.hard_link, item.metadata) {
+            groups.entry(id).or_default().push(item);
+        }
+    }
+
+    // Destination items are matched the same way as when building the diff
+    let dest_items = dest
+        .items
+        .iter()
+        .map(|item| (options.path_key(&item.path), item))
+        .collect::<HashMap<_, _>>();
+
+    let changed = diff.iter().map(|item| &item.path).collect::<HashSet<_>>();
+
+    let mut links = HashMap::new();
+
+    for members in groups.values_mut() {
+        if members.len() < 2 {
+            continue;
+        }
+
+        members.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let leader = members[0];
+        let leader_dest = dest_items.get(&options.path_key(&leader.path));
+
+        let leader_dest_link = leader_dest
+            .and_then(|item| item.hard_link)
+            .filter(|_| !changed.contains(&leader.path));
+
+        for member in &members[1..] {
+            let DriverItemMetadata::File(new) = member.metadata else {
+                unreachable!()
+            };
+
+            let dest_item = dest_items.get(&options.path_key(&member.path));
+
+            if leader_dest_link.is_some()
+                && dest_item.and_then(|item| item.hard_link) == leader_dest_link
+            {
+                continue;
+            }
+
+            links.insert(
+                member.path.clone(),
+                DiffItem {
+                    path: member.path.clone(),
+                    status: DiffType::HardLinked(DiffItemHardLinked {
+                        target: leader.path.clone(),
+                        dest_target: leader_dest.and_then(|item| renamed_dest_path(leader, item)),
+                        prev: dest_item.map(|item| item.metadata),
+                        new,
+                    }),
+                    dest_path: dest_item.and_then(|item| renamed_dest_path(member, item)),
+                },
+            );
+        }
+    }
+
+    diff.retain(|item| !links.contains_key(&item.path));
+    diff.extend(links.into_values());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diffing::build_diff,
+        drivers::{DriverFileMetadata, DriverItemPermissions, ItemPath},
+        events::Events,
+    };
+
+    fn path(path: &str) -> ItemPath {
+        ItemPath::new(path.as_bytes().to_vec())
+    }
+
+    fn file(item_path: &str, inode: Option<u64>) -> DriverItem {
+        DriverItem {
+            path: path(item_path),
+            metadata: DriverItemMetadata::File(DriverFileMetadata {
+                modification_date: 1,
+                modification_date_nanos: None,
+                size: 1,
+                allocated_size: None,
+            }),
+            permissions: DriverItemPermissions::default(),
+            xattrs: None,
+            hard_link: inode.map(|inode| HardLinkId { device: 1, inode }),
+        }
+    }
+
+    fn snapshot(items: Vec<DriverItem>) -> Snapshot {
+        Snapshot {
+            path: String::new(),
+            items,
+        }
+    }
+
+    /// Changes between two snapshots, with hard links resolved
+    fn resolve(source: Vec<DriverItem>, dest: Vec<DriverItem>) -> Vec<(String, DiffType)> {
+        let options = DiffOptions {
+            hard_links: true,
+            ..DiffOptions::default()
+        };
+
+        let mut diff = build_diff(&snapshot(source), &snapshot(dest), &options, &Events::new());
+        diff.sort();
+
+        diff.into_items()
+            .into_iter()
+            .map(|item| (item.path.to_string(), item.status))
+            .collect()
+    }
+
+    fn target(status: &DiffType) -> Option<String> {
+        match status {
+            DiffType::HardLinked(linked) => Some(linked.target.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn links_target_unchanged_files() {
+        // The leader is identical on both sides so it isn't part of the diff
+        let changes = resolve(
+            vec![file("a", Some(1)), file("b", Some(1))],
+            vec![file("a", None)],
+        );
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, "b");
+        assert_eq!(target(&changes[0].1).as_deref(), Some("a"));
+
+        // Nothing to do when the destination already has the same links
+        assert!(resolve(
+            vec![file("a", Some(1)), file("b", Some(1))],
+            vec![file("a", Some(7)), file("b", Some(7))],
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn all_links_of_a_group_target_its_leader() {
+        let source = vec![
+            file("c", Some(1)),
+            file("a", Some(1)),
+            file("b", Some(1)),
+            file("single", Some(2)),
+        ];
+
+        let changes = resolve(source.clone(), vec![]);
+        let targets = changes
+            .iter()
+            .map(|(path, status)| (path.as_str(), target(status)))
+            .collect::<Vec<_>>();
+
+        // Links are never chained, and files with a single link are copied as usual
+        assert_eq!(
+            targets,
+            [
+                ("a", None),
+                ("single", None),
+                ("b", Some("a".to_string())),
+                ("c", Some("a".to_string())),
+            ]
+        );
+
+        // Links between other members of the group in the destination aren't enough
+        let changes = resolve(
+            source,
+            vec![
+                file("a", Some(7)),
+                file("b", Some(8)),
+                file("c", Some(8)),
+                file("single", None),
+            ],
+        );
+
+        let paths = changes
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(paths, ["b", "c"]);
+    }
+}
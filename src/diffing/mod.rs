@@ -1,5 +1,18 @@
 mod categorized;
+mod checksum;
 mod diff;
+mod hard_links;
+mod names;
+mod safety;
+mod summary;
+mod three_way;
+mod tree;
 
 pub use categorized::*;
+pub use checksum::*;
 pub use diff::*;
+pub use names::*;
+pub use safety::*;
+pub use summary::*;
+pub use three_way::*;
+pub use tree::*;
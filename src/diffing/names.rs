@@ -0,0 +1,136 @@
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+
+use anyhow::{bail, Error};
+use unicode_normalization::UnicodeNormalization as _;
+
+use super::DiffOptions;
+use crate::drivers::{ItemPath, Snapshot};
+
+/// Unicode normalization form applied to paths before comparing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeNormalization {
+    /// Composed form (used by most Linux and Windows tools)
+    Nfc,
+
+    /// Decomposed form (used by macOS filesystems)
+    Nfd,
+}
+
+impl FromStr for UnicodeNormalization {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            _ => bail!(
+                "Unknown normalization form '{}' (expected 'nfc' or 'nfd')",
+                s
+            ),
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Get the key used to match a path against the other side's paths
+    ///
+    /// Paths which aren't valid UTF-8 are always compared byte-exactly
+    pub fn path_key<'a>(&self, path: &'a ItemPath) -> Cow<'a, ItemPath> {
+        if self.normalization.is_none() && !self.case_insensitive {
+            return Cow::Borrowed(path);
+        }
+
+        let Some(str_path) = path.to_str() else {
+            return Cow::Borrowed(path);
+        };
+
+        let normalize = |s: &str| match self.normalization {
+            Some(UnicodeNormalization::Nfc) => s.nfc().collect::<String>(),
+            Some(UnicodeNormalization::Nfd) => s.nfd().collect::<String>(),
+            None => s.to_string(),
+        };
+
+        let mut key = normalize(str_path);
+
+        if self.case_insensitive {
+            // Folding may decompose characters, so the key is normalized again
+            key = normalize(&case_fold(&key));
+        }
+
+        Cow::Owned(ItemPath::new(key.into_bytes()))
+    }
+}
+
+/// Fold the case of a string, so strings only differing by case get the same key
+///
+/// Lowercasing alone isn't enough as some characters only match once uppercased (e.g. `ß` and
+/// `SS`, or `ﬁ` and `FI`). This approximates Unicode's full case folding without its locale
+/// specific rules (e.g. the Turkish dotless `ı` matches `I` and `i`).
+fn case_fold(s: &str) -> String {
+    s.to_uppercase().to_lowercase()
+}
+
+/// Find items of a snapshot whose names would collide once normalized or case-folded,
+/// e.g. `Photo.JPG` and `photo.jpg` on a case-insensitive destination
+pub fn find_name_collisions<'a>(
+    snapshot: &'a Snapshot,
+    options: &DiffOptions,
+) -> Vec<Vec<&'a ItemPath>> {
+    let mut keys = HashMap::<_, Vec<_>>::new();
+
+    for item in &snapshot.items {
+        keys.entry(options.path_key(&item.path))
+            .or_default()
+            .push(&item.path);
+    }
+
+    let mut collisions = keys
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|mut paths| {
+            paths.sort();
+            paths
+        })
+        .collect::<Vec<_>>();
+
+    collisions.sort();
+    collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str, options: &DiffOptions) -> ItemPath {
+        options
+            .path_key(&ItemPath::new(path.as_bytes().to_vec()))
+            .into_owned()
+    }
+
+    #[test]
+    fn case_insensitive_keys_are_folded() {
+        let options = DiffOptions {
+            case_insensitive: true,
+            ..DiffOptions::default()
+        };
+
+        assert_eq!(key("Photo.JPG", &options), key("photo.jpg", &options));
+        assert_eq!(key("Straße", &options), key("STRASSE", &options));
+        assert_eq!(key("ﬁle", &options), key("FILE", &options));
+        assert_ne!(key("a", &options), key("b", &options));
+    }
+
+    #[test]
+    fn folded_keys_are_normalized() {
+        let options = DiffOptions {
+            case_insensitive: true,
+            normalization: Some(UnicodeNormalization::Nfc),
+            ..DiffOptions::default()
+        };
+
+        assert_eq!(
+            key("E\u{301}t\u{e9}", &options),
+            key("\u{c9}T\u{c9}", &options)
+        );
+    }
+}
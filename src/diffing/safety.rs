@@ -0,0 +1,208 @@
+use anyhow::{bail, Result};
+
+use crate::drivers::{DriverItemMetadata, ItemPath, Snapshot};
+
+/// Share of the baseline's items above which deletions propagated by a bidirectional
+/// synchronization are refused by default
+const MAX_SYNC_DELETE_PERCENT: f64 = 90.0;
+
+/// Number of deletions below which [`MAX_SYNC_DELETE_PERCENT`] doesn't apply, so small
+/// directories can still be emptied
+const MIN_SYNC_DELETE_COUNT: usize = 10;
+
+/// Guards against applying a diff that would wipe out the destination,
+/// e.g. because the source is an unmounted drive or a wrong path
+#[derive(Debug, Clone, Default)]
+pub struct SafetyChecks {
+    /// Maximum number of items that may be deleted from the destination
+    pub max_delete: Option<usize>,
+
+    /// Maximum percentage (0 to 100) of the destination's items that may be deleted
+    pub max_delete_percent: Option<f64>,
+
+    /// Allow an empty source (refused by default), or an empty or almost emptied replica
+    /// in bidirectional synchronization
+    pub allow_empty_source: bool,
+
+    /// Marker file that must exist at the root of the source
+    pub source_marker: Option<String>,
+
+    /// Marker file that must exist at the root of the destination
+    pub dest_marker: Option<String>,
+}
+
+impl SafetyChecks {
+    /// Check the snapshots themselves, before any diff is computed
+    pub fn check_snapshots(&self, source: &Snapshot, dest: &Snapshot) -> Result<()> {
+        if !self.allow_empty_source && source.items.is_empty() && !dest.items.is_empty() {
+            bail!(
+                "Source directory '{}' is empty while destination is not, refusing to continue (is the source drive mounted?)",
+                source.path
+            );
+        }
+
+        if let Some(marker) = &self.source_marker {
+            check_marker(source, marker, "source")?;
+        }
+
+        if let Some(marker) = &self.dest_marker {
+            check_marker(dest, marker, "destination")?;
+        }
+
+        Ok(())
+    }
+
+    /// Check each mapped directory of a merged source (see [`crate::drivers::merged::MergedDriver`]),
+    /// as the merged snapshot itself is never empty
+    pub fn check_mappings(
+        &self,
+        source: &Snapshot,
+        dest: &Snapshot,
+        prefixes: &[ItemPath],
+    ) -> Result<()> {
+        if self.allow_empty_source {
+            return Ok(());
+        }
+
+        for prefix in prefixes {
+            let has_items = |snapshot: &Snapshot| {
+                snapshot
+                    .items
+                    .iter()
+                    .any(|item| item.path.is_inside(prefix))
+            };
+
+            if !has_items(source) && has_items(dest) {
+                bail!(
+                    "Source directory mapped to '{}' is empty while destination is not, refusing to continue (is the source drive mounted?)",
+                    prefix
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the replicas of a bidirectional synchronization, where each one is the source of
+    /// the other: an empty replica (e.g. an unmounted drive) would have all of the baseline's
+    /// items deleted from the other one
+    pub fn check_replicas(&self, a: &Snapshot, b: &Snapshot, baseline: &Snapshot) -> Result<()> {
+        if self.allow_empty_source || baseline.items.is_empty() {
+            return Ok(());
+        }
+
+        for replica in [a, b] {
+            if replica.items.is_empty() {
+                bail!(
+                    "Directory '{}' is empty while the baseline is not, refusing to continue (is the drive mounted?)",
+                    replica.path
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the number of deletions a bidirectional synchronization would propagate to one
+    /// of the replicas against the size of the baseline, refusing to delete (almost) all of it
+    /// unless empty sources are allowed or only a few items are deleted
+    pub fn check_sync_deletions(
+        &self,
+        replica: &str,
+        delete_count: usize,
+        baseline_items: usize,
+    ) -> Result<()> {
+        if self.allow_empty_source || delete_count < MIN_SYNC_DELETE_COUNT {
+            return Ok(());
+        }
+
+        let percent = delete_count as f64 * 100.0 / baseline_items as f64;
+
+        if percent >= MAX_SYNC_DELETE_PERCENT {
+            bail!(
+                "Refusing to delete {} out of {} synchronized items from '{}' ({:.2}%) as the other directory was almost entirely emptied (use --allow-empty-source if this is intended)",
+                delete_count,
+                baseline_items,
+                replica,
+                percent
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check the number of deletions against the configured thresholds
+    ///
+    /// `dest_items` is the total number of items in the destination snapshot
+    pub fn check_deletions(&self, delete_count: usize, dest_items: usize) -> Result<()> {
+        if let Some(max) = self.max_delete {
+            if delete_count > max {
+                bail!(
+                    "Refusing to delete {} items as it exceeds the maximum of {} deletions",
+                    delete_count,
+                    max
+                );
+            }
+        }
+
+        if let Some(max_percent) = self.max_delete_percent {
+            if dest_items > 0 {
+                let percent = delete_count as f64 * 100.0 / dest_items as f64;
+
+                if percent > max_percent {
+                    bail!(
+                        "Refusing to delete {} out of {} items in destination ({:.2}%) as it exceeds the maximum of {}%",
+                        delete_count,
+                        dest_items,
+                        percent,
+                        max_percent
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_marker(snapshot: &Snapshot, marker: &str, side: &str) -> Result<()> {
+    let found = snapshot.items.iter().any(|item| {
+        item.path.as_bytes() == marker.as_bytes()
+            && matches!(item.metadata, DriverItemMetadata::File(_))
+    });
+
+    if !found {
+        bail!(
+            "Marker file '{}' was not found in {} directory '{}', refusing to continue (is the drive mounted?)",
+            marker,
+            side,
+            snapshot.path
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn few_sync_deletions_are_allowed() {
+        let safety = SafetyChecks::default();
+        let check = |delete_count, baseline_items| {
+            safety
+                .check_sync_deletions("a", delete_count, baseline_items)
+                .is_ok()
+        };
+
+        assert!(check(0, 0));
+        assert!(check(1, 1));
+        assert!(check(MIN_SYNC_DELETE_COUNT - 1, MIN_SYNC_DELETE_COUNT - 1));
+        assert!(!check(MIN_SYNC_DELETE_COUNT, MIN_SYNC_DELETE_COUNT));
+
+        // 90% is the first refused share
+        assert!(check(89, 100));
+        assert!(!check(90, 100));
+    }
+}
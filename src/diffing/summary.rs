@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::drivers::{ItemPath, SizeMode};
+
+use super::CategorizedDiff;
+
+/// Number of items and total size for one category of changes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SummaryCounter {
+    pub count: usize,
+    pub size: u64,
+}
+
+impl SummaryCounter {
+    pub(super) fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.size += size;
+    }
+}
+
+/// Aggregated changes for all items inside a directory (recursively)
+#[derive(Debug, Clone)]
+pub struct DirectorySummary {
+    /// Path of the directory, empty for the root
+    pub path: ItemPath,
+    pub added: SummaryCounter,
+    pub modified: SummaryCounter,
+    pub metadata_changed: SummaryCounter,
+    pub hard_linked: SummaryCounter,
+    pub type_changed: SummaryCounter,
+    pub deleted: SummaryCounter,
+}
+
+impl DirectorySummary {
+    fn new(path: ItemPath) -> Self {
+        Self {
+            path,
+            added: SummaryCounter::default(),
+            modified: SummaryCounter::default(),
+            metadata_changed: SummaryCounter::default(),
+            hard_linked: SummaryCounter::default(),
+            type_changed: SummaryCounter::default(),
+            deleted: SummaryCounter::default(),
+        }
+    }
+
+    fn counters(&self) -> [SummaryCounter; 6] {
+        [
+            self.added,
+            self.modified,
+            self.metadata_changed,
+            self.hard_linked,
+            self.type_changed,
+            self.deleted,
+        ]
+    }
+
+    /// Total number of changed items
+    pub fn total_count(&self) -> usize {
+        self.counters().iter().map(|counter| counter.count).sum()
+    }
+
+    /// Total size of the changed items (both transferred and deleted)
+    pub fn total_size(&self) -> u64 {
+        self.counters().iter().map(|counter| counter.size).sum()
+    }
+}
+
+impl CategorizedDiff {
+    /// Group changes by directory, `depth` being the number of path components to keep
+    /// (`0` groups everything under the root)
+    ///
+    /// Each item is counted in every parent directory up to that depth, the root included.
+    /// Directories are sorted by total size, then by number of changes
+    pub fn summarize(&self, depth: usize, size_mode: SizeMode) -> Vec<DirectorySummary> {
+        let mut dirs = HashMap::<ItemPath, DirectorySummary>::new();
+
+        for (path, added) in &self.added {
+            let size = added.new.size_with(size_mode).unwrap_or(0);
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.added.add(size));
+        }
+
+        for (path, modified) in &self.modified {
+            let size = modified.new.size_with(size_mode);
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.modified.add(size));
+        }
+
+        for (path, _) in &self.metadata_changed {
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.metadata_changed.add(0));
+        }
+
+        for (path, _) in &self.hard_linked {
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.hard_linked.add(0));
+        }
+
+        for (path, type_changed) in &self.type_changed {
+            let size = type_changed.new.size_with(size_mode).unwrap_or(0);
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.type_changed.add(size));
+        }
+
+        for (path, deleted) in &self.deleted {
+            let size = deleted.prev.size_with(size_mode).unwrap_or(0);
+            add_to_ancestors(&mut dirs, path, depth, |dir| dir.deleted.add(size));
+        }
+
+        let mut dirs = dirs.into_values().collect::<Vec<_>>();
+
+        dirs.sort_by(|a, b| {
+            b.total_size()
+                .cmp(&a.total_size())
+                .then_with(|| b.total_count().cmp(&a.total_count()))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+
+        dirs
+    }
+}
+
+/// Count an item in each of its parent directories, up to `depth` components deep
+fn add_to_ancestors(
+    dirs: &mut HashMap<ItemPath, DirectorySummary>,
+    path: &ItemPath,
+    depth: usize,
+    add: impl Fn(&mut DirectorySummary),
+) {
+    let mut add_to = |dir: &Path| {
+        let dir = ItemPath::from_path(dir);
+
+        add(dirs
+            .entry(dir.clone())
+            .or_insert_with(|| DirectorySummary::new(dir)));
+    };
+
+    let mut dir = PathBuf::new();
+    add_to(&dir);
+
+    if let Some(parent) = path.as_path().parent() {
+        for component in parent.components().take(depth) {
+            dir.push(component);
+            add_to(&dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diffing::{Diff, DiffItem, DiffItemAdded, DiffItemDeleted, DiffItemModified, DiffType},
+        drivers::{DriverFileMetadata, DriverItemMetadata, DriverItemPermissions},
+    };
+
+    fn file(size: u64) -> DriverFileMetadata {
+        DriverFileMetadata {
+            modification_date: 0,
+            modification_date_nanos: None,
+            size,
+            allocated_size: None,
+        }
+    }
+
+    fn item(path: &str, status: DiffType) -> DiffItem {
+        DiffItem {
+            path: ItemPath::new(path.as_bytes().to_vec()),
+            status,
+            dest_path: None,
+        }
+    }
+
+    fn added(path: &str, new: DriverItemMetadata) -> DiffItem {
+        item(
+            path,
+            DiffType::Added(DiffItemAdded {
+                new,
+                permissions: DriverItemPermissions::default(),
+            }),
+        )
+    }
+
+    /// Number of changes and total size of each summarized directory, by path
+    fn summarize(depth: usize) -> Vec<(String, usize, u64)> {
+        let diff = Diff::new(vec![
+            added("a/b/c", DriverItemMetadata::Directory),
+            added("a/b/c/file", DriverItemMetadata::File(file(10))),
+            item(
+                "a/b/other",
+                DiffType::Modified(DiffItemModified {
+                    prev: file(1),
+                    new: file(5),
+                    metadata_changed: None,
+                }),
+            ),
+            item(
+                "a/top",
+                DiffType::Deleted(DiffItemDeleted {
+                    prev: DriverItemMetadata::File(file(3)),
+                }),
+            ),
+            added("root", DriverItemMetadata::File(file(1))),
+        ]);
+
+        let mut dirs = CategorizedDiff::new(diff)
+            .summarize(depth, SizeMode::Apparent)
+            .into_iter()
+            .map(|dir| (dir.path.to_string(), dir.total_count(), dir.total_size()))
+            .collect::<Vec<_>>();
+
+        dirs.sort();
+        dirs
+    }
+
+    #[test]
+    fn items_are_counted_in_every_ancestor() {
+        assert_eq!(summarize(0), [(String::new(), 5, 19)]);
+
+        assert_eq!(
+            summarize(2),
+            [
+                (String::new(), 5, 19),
+                ("a".to_string(), 4, 18),
+                ("a/b".to_string(), 3, 15),
+            ]
+        );
+
+        // Directories are counted in their parents, not in themselves
+        assert_eq!(
+            summarize(10),
+            [
+                (String::new(), 5, 19),
+                ("a".to_string(), 4, 18),
+                ("a/b".to_string(), 3, 15),
+                ("a/b/c".to_string(), 1, 10),
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+
+use super::{build_diff, DiffOptions, DiffType};
+use crate::{
+    drivers::{DriverItemMetadata, ItemPath, Snapshot},
+    events::Events,
+};
+
+/// Changes of two replicas relative to the snapshot they were last in sync with
+pub struct ThreeWayDiff(Vec<ThreeWayItem>);
+
+impl ThreeWayDiff {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn items(&self) -> &[ThreeWayItem] {
+        &self.0
+    }
+
+    pub fn into_items(self) -> Vec<ThreeWayItem> {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreeWayItem {
+    pub path: ItemPath,
+    pub status: ThreeWayStatus,
+}
+
+/// Classification of a path, changes being described from the baseline to each replica
+/// (the baseline being the "destination" of the [`DiffType`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThreeWayStatus {
+    /// Only changed in the first replica
+    ChangedInA(DiffType),
+
+    /// Only changed in the second replica
+    ChangedInB(DiffType),
+
+    /// Changed the same way in both replicas
+    ChangedInBoth { a: DiffType, b: DiffType },
+
+    /// Changed differently in both replicas, or a directory was removed on one side while
+    /// some of its content changed on the other (the unchanged side is then `None`)
+    Conflict {
+        a: Option<DiffType>,
+        b: Option<DiffType>,
+    },
+}
+
+impl ThreeWayStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChangedInA(_) => "changed-in-a",
+            Self::ChangedInB(_) => "changed-in-b",
+            Self::ChangedInBoth { .. } => "changed-in-both",
+            Self::Conflict { .. } => "conflict",
+        }
+    }
+
+    /// Change made to the first replica, if any
+    pub fn a(&self) -> Option<&DiffType> {
+        match self {
+            Self::ChangedInA(a) | Self::ChangedInBoth { a, .. } => Some(a),
+            Self::Conflict { a, .. } => a.as_ref(),
+            Self::ChangedInB(_) => None,
+        }
+    }
+
+    /// Change made to the second replica, if any
+    pub fn b(&self) -> Option<&DiffType> {
+        match self {
+            Self::ChangedInB(b) | Self::ChangedInBoth { b, .. } => Some(b),
+            Self::Conflict { b, .. } => b.as_ref(),
+            Self::ChangedInA(_) => None,
+        }
+    }
+}
+
+/// Compare two replicas to a baseline snapshot
+///
+/// Paths are compared exactly, hard links aren't detected and modification dates aren't shifted,
+/// whatever the options say (the baseline was taken from the replicas themselves). No diff phase
+/// is reported, as the source and destination they refer to would be meaningless here.
+pub fn build_three_way_diff(
+    baseline: &Snapshot,
+    a: Snapshot,
+    b: Snapshot,
+    options: &DiffOptions,
+) -> ThreeWayDiff {
+    let options = DiffOptions {
+        hard_links: false,
+        normalization: None,
+        case_insensitive: false,
+        mtime_offset: 0,
+        ..*options
+    };
+
+    let changes = |replica: Snapshot| {
+        build_diff(&replica, baseline, &options, &Events::new())
+            .into_items()
+            .into_iter()
+            .map(|item| (item.path, item.status))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let mut a_changes = changes(a);
+    let mut b_changes = changes(b);
+
+    let mut paths = a_changes.keys().cloned().collect::<Vec<_>>();
+    paths.extend(b_changes.keys().cloned());
+    paths.sort();
+    paths.dedup();
+
+    let mut items = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let status = match (a_changes.remove(&path), b_changes.remove(&path)) {
+            (Some(a), None) => ThreeWayStatus::ChangedInA(a),
+            (None, Some(b)) => ThreeWayStatus::ChangedInB(b),
+            (Some(a), Some(b)) if a.has_same_result(&b, &options) => {
+                ThreeWayStatus::ChangedInBoth { a, b }
+            }
+            (Some(a), Some(b)) => ThreeWayStatus::Conflict {
+                a: Some(a),
+                b: Some(b),
+            },
+            (None, None) => unreachable!(),
+        };
+
+        items.push(ThreeWayItem { path, status });
+    }
+
+    mark_removed_dirs_conflicts(&mut items);
+
+    ThreeWayDiff(items)
+}
+
+/// Removing a directory (or replacing it with another type of item) on one side while
+/// something changed inside of it on the other side is a conflict
+fn mark_removed_dirs_conflicts(items: &mut [ThreeWayItem]) {
+    let removes_dir = |change: &DiffType| {
+        matches!(change.prev_metadata(), Some(DriverItemMetadata::Directory))
+            && !matches!(change.new_metadata(), Some(DriverItemMetadata::Directory))
+    };
+
+    for i in 0..items.len() {
+        let (a_removes, b_removes) = match &items[i].status {
+            ThreeWayStatus::ChangedInA(a) => (removes_dir(a), false),
+            ThreeWayStatus::ChangedInB(b) => (false, removes_dir(b)),
+            _ => continue,
+        };
+
+        if !a_removes && !b_removes {
+            continue;
+        }
+
+        let dir = &items[i].path;
+
+        let mut prefix = dir.as_bytes().to_vec();
+        prefix.push(b'/');
+
+        // Items are sorted by path, so those inside the directory (all starting with `dir/`)
+        // are contiguous, though not necessarily right after it (`a-b` sorts between `a` and `a/b`)
+        let start =
+            i + 1 + items[i + 1..].partition_point(|item| item.path.as_bytes() < &prefix[..]);
+
+        let changed_inside = items[start..]
+            .iter()
+            .take_while(|item| item.path.is_inside(dir))
+            .any(|item| match &item.status {
+                ThreeWayStatus::ChangedInA(change) => b_removes && change.new_metadata().is_some(),
+                ThreeWayStatus::ChangedInB(change) => a_removes && change.new_metadata().is_some(),
+                ThreeWayStatus::ChangedInBoth { .. } => false,
+                ThreeWayStatus::Conflict { .. } => true,
+            });
+
+        if changed_inside {
+            items[i].status = match &items[i].status {
+                ThreeWayStatus::ChangedInA(a) => ThreeWayStatus::Conflict {
+                    a: Some(a.clone()),
+                    b: None,
+                },
+                ThreeWayStatus::ChangedInB(b) => ThreeWayStatus::Conflict {
+                    a: None,
+                    b: Some(b.clone()),
+                },
+                _ => unreachable!(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::{DriverFileMetadata, DriverItem, DriverItemPermissions};
+
+    fn item(path: &str, metadata: DriverItemMetadata) -> DriverItem {
+        DriverItem {
+            path: ItemPath::new(path.as_bytes().to_vec()),
+            metadata,
+            permissions: DriverItemPermissions::default(),
+            xattrs: None,
+            hard_link: None,
+        }
+    }
+
+    fn file(path: &str, size: u64) -> DriverItem {
+        item(
+            path,
+            DriverItemMetadata::File(DriverFileMetadata {
+                modification_date: 0,
+                modification_date_nanos: None,
+                size,
+                allocated_size: None,
+            }),
+        )
+    }
+
+    fn dir(path: &str) -> DriverItem {
+        item(path, DriverItemMetadata::Directory)
+    }
+
+    fn snapshot(items: Vec<DriverItem>) -> Snapshot {
+        Snapshot {
+            path: String::new(),
+            items,
+        }
+    }
+
+    fn status_of(b_items: Vec<DriverItem>) -> ThreeWayStatus {
+        let baseline = snapshot(vec![dir("a"), file("a/x", 1), file("a-c", 1)]);
+
+        // The directory is removed from the first replica
+        let a = snapshot(vec![file("a-c", 1)]);
+
+        let diff = build_three_way_diff(&baseline, a, snapshot(b_items), &DiffOptions::default());
+
+        diff.into_items()
+            .into_iter()
+            .find(|item| item.path.as_bytes() == b"a")
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn removed_dir_conflicts_with_changes_inside() {
+        let status = status_of(vec![
+            dir("a"),
+            file("a/x", 1),
+            file("a/new", 1),
+            file("a-c", 1),
+        ]);
+
+        assert!(matches!(
+            status,
+            ThreeWayStatus::Conflict {
+                a: Some(_),
+                b: None
+            }
+        ));
+    }
+
+    #[test]
+    fn removed_dir_ignores_changes_to_siblings() {
+        // `a-c` sorts between `a` and `a/x`, but isn't inside `a`
+        let status = status_of(vec![
+            dir("a"),
+            file("a/x", 1),
+            file("a-c", 2),
+            file("a-d", 1),
+        ]);
+
+        assert!(matches!(status, ThreeWayStatus::ChangedInA(_)));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use crate::drivers::{DriverFileMetadata, DriverItemMetadata, ItemPath, SizeMode};
+
+use super::{CategorizedDiff, SummaryCounter};
+
+/// Change of a single item in a [`DiffTree`]
+#[derive(Debug, Clone, Copy)]
+pub enum DiffTreeChange {
+    Added(DriverItemMetadata),
+    Modified(DriverFileMetadata),
+    TypeChanged {
+        prev: DriverItemMetadata,
+        new: DriverItemMetadata,
+    },
+    /// Only permissions, ownership or extended attributes changed
+    MetadataChanged(DriverItemMetadata),
+    /// Hard link to another file of the source
+    HardLinked(DriverFileMetadata),
+    Deleted(DriverItemMetadata),
+}
+
+impl DiffTreeChange {
+    /// Metadata of the item as it will be after the changes are applied
+    /// (or was before being deleted)
+    pub fn metadata(&self) -> DriverItemMetadata {
+        match *self {
+            Self::Added(m) | Self::MetadataChanged(m) | Self::Deleted(m) => m,
+            Self::Modified(m) | Self::HardLinked(m) => DriverItemMetadata::File(m),
+            Self::TypeChanged { new, .. } => new,
+        }
+    }
+
+    /// Size of the item, unless the change has no content to transfer (like in summaries)
+    pub fn size_with(&self, size_mode: SizeMode) -> Option<u64> {
+        match self {
+            Self::MetadataChanged(_) | Self::HardLinked(_) => None,
+            _ => self.metadata().size_with(size_mode),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiffTreeNode {
+    /// Name of the node, empty for the root
+    pub name: ItemPath,
+
+    /// Change of the item itself (`None` for directories which only have changed descendants)
+    pub change: Option<DiffTreeChange>,
+
+    /// Descendants collapsed into this node, as they were all added or deleted along with it
+    pub collapsed: SummaryCounter,
+
+    /// Children by name
+    pub children: BTreeMap<ItemPath, DiffTreeNode>,
+}
+
+/// Changed items arranged as a tree
+///
+/// Directories which are entirely added or deleted are collapsed into a single node
+#[derive(Debug)]
+pub struct DiffTree {
+    pub root: DiffTreeNode,
+}
+
+impl CategorizedDiff {
+    pub fn to_tree(&self, size_mode: SizeMode) -> DiffTree {
+        // Directories whose whole content is added (resp. deleted)
+        let added_dirs = self
+            .added
+            .iter()
+            .filter(|(_, i)| i.new.is_dir())
+            .map(|(path, _)| path.as_path())
+            .chain(
+                self.type_changed
+                    .iter()
+                    .filter(|(_, i)| i.new.is_dir())
+                    .map(|(path, _)| path.as_path()),
+            )
+            .collect::<HashSet<_>>();
+
+        let deleted_dirs = self
+            .deleted
+            .iter()
+            .filter(|(_, i)| i.prev.is_dir())
+            .map(|(path, _)| path.as_path())
+            .chain(
+                self.type_changed
+                    .iter()
+                    .filter(|(_, i)| i.prev.is_dir())
+                    .map(|(path, _)| path.as_path()),
+            )
+            .collect::<HashSet<_>>();
+
+        let mut root = DiffTreeNode::default();
+
+        let mut insert =
+            |path: &ItemPath, change: DiffTreeChange, collapse_into: &HashSet<&Path>| {
+                match collapsing_ancestor(path.as_path(), collapse_into) {
+                    Some(ancestor) => {
+                        let size = change.size_with(size_mode).unwrap_or(0);
+                        root.node_mut(ancestor).collapsed.add(size);
+                    }
+                    None => root.node_mut(path.as_path()).change = Some(change),
+                }
+            };
+
+        for (path, added) in &self.added {
+            insert(path, DiffTreeChange::Added(added.new), &added_dirs);
+        }
+
+        for (path, modified) in &self.modified {
+            insert(
+                path,
+                DiffTreeChange::Modified(modified.new),
+                &HashSet::new(),
+            );
+        }
+
+        for (path, type_changed) in &self.type_changed {
+            let change = DiffTreeChange::TypeChanged {
+                prev: type_changed.prev,
+                new: type_changed.new,
+            };
+
+            insert(path, change, &HashSet::new());
+        }
+
+        for (path, changed) in &self.metadata_changed {
+            insert(
+                path,
+                DiffTreeChange::MetadataChanged(changed.item),
+                &HashSet::new(),
+            );
+        }
+
+        // Links created in a new directory are collapsed into it like the rest of its content
+        for (path, linked) in &self.hard_linked {
+            insert(path, DiffTreeChange::HardLinked(linked.new), &added_dirs);
+        }
+
+        for (path, deleted) in &self.deleted {
+            insert(path, DiffTreeChange::Deleted(deleted.prev), &deleted_dirs);
+        }
+
+        DiffTree { root }
+    }
+}
+
+impl DiffTreeNode {
+    /// Get the node at the provided path, creating it and its parents if needed
+    fn node_mut(&mut self, path: &Path) -> &mut DiffTreeNode {
+        path.components().fold(self, |node, component| {
+            let name = ItemPath::from_path(Path::new(component.as_os_str()));
+
+            node.children
+                .entry(name.clone())
+                .or_insert_with(|| DiffTreeNode {
+                    name,
+                    ..Default::default()
+                })
+        })
+    }
+}
+
+/// Find the topmost (strict) ancestor of a path which is part of the provided set
+fn collapsing_ancestor<'a>(path: &Path, dirs: &HashSet<&'a Path>) -> Option<&'a Path> {
+    let mut ancestors = path.ancestors().skip(1).collect::<Vec<_>>();
+    ancestors.reverse();
+
+    ancestors
+        .into_iter()
+        .find_map(|ancestor| dirs.get(ancestor).copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diffing::{
+            Diff, DiffItem, DiffItemAdded, DiffItemHardLinked, DiffItemMetadataChanged, DiffType,
+        },
+        drivers::DriverItemPermissions,
+    };
+
+    fn path(path: &str) -> ItemPath {
+        ItemPath::new(path.as_bytes().to_vec())
+    }
+
+    fn file(size: u64) -> DriverFileMetadata {
+        DriverFileMetadata {
+            modification_date: 0,
+            modification_date_nanos: None,
+            size,
+            allocated_size: None,
+        }
+    }
+
+    fn item(item_path: &str, status: DiffType) -> DiffItem {
+        DiffItem {
+            path: path(item_path),
+            status,
+            dest_path: None,
+        }
+    }
+
+    fn added(item_path: &str, new: DriverItemMetadata) -> DiffItem {
+        item(
+            item_path,
+            DiffType::Added(DiffItemAdded {
+                new,
+                permissions: DriverItemPermissions::default(),
+            }),
+        )
+    }
+
+    fn linked(item_path: &str, target: &str, size: u64) -> DiffItem {
+        item(
+            item_path,
+            DiffType::HardLinked(DiffItemHardLinked {
+                target: path(target),
+                dest_target: None,
+                prev: None,
+                new: file(size),
+            }),
+        )
+    }
+
+    fn child<'a>(node: &'a DiffTreeNode, name: &str) -> &'a DiffTreeNode {
+        &node.children[&path(name)]
+    }
+
+    #[test]
+    fn metadata_changes_and_hard_links_are_in_the_tree() {
+        let diff = Diff::new(vec![
+            item(
+                "docs",
+                DiffType::MetadataChanged(DiffItemMetadataChanged {
+                    item: DriverItemMetadata::Directory,
+                    prev: DriverItemPermissions::default(),
+                    new: DriverItemPermissions::default(),
+                    xattrs_changed: true,
+                }),
+            ),
+            added("docs/original", DriverItemMetadata::File(file(10))),
+            linked("docs/link", "docs/original", 10),
+            added("new", DriverItemMetadata::Directory),
+            added("new/original", DriverItemMetadata::File(file(20))),
+            linked("new/link", "new/original", 20),
+        ]);
+
+        let tree = CategorizedDiff::new(diff).to_tree(SizeMode::Apparent);
+
+        let docs = child(&tree.root, "docs");
+        assert!(matches!(
+            docs.change,
+            Some(DiffTreeChange::MetadataChanged(
+                DriverItemMetadata::Directory
+            ))
+        ));
+        assert!(matches!(
+            child(docs, "link").change,
+            Some(DiffTreeChange::HardLinked(_))
+        ));
+        assert!(matches!(
+            child(docs, "original").change,
+            Some(DiffTreeChange::Added(_))
+        ));
+
+        // Links are collapsed into new directories without adding to their size
+        let new = child(&tree.root, "new");
+        assert!(new.children.is_empty());
+        assert_eq!(new.collapsed.count, 2);
+        assert_eq!(new.collapsed.size, 20);
+    }
+}
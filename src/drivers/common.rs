@@ -1,14 +1,20 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    io::Read,
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Error, Result};
 
-#[derive(Debug)]
+use super::{ItemPath, WalkFilters};
+use crate::events::{Event, Events, Side};
+
+#[derive(Debug, Clone)]
 pub struct Snapshot {
     // TODO: add checksum
     // TODO: add creation date
@@ -16,20 +22,49 @@ pub struct Snapshot {
     pub items: Vec<DriverItem>,
 }
 
+impl Snapshot {
+    /// Only keep the items covered by one of the prefixes (or which are one of their parents),
+    /// so the rest of a destination is never compared and so never deleted
+    pub fn retain_mapped(&mut self, prefixes: &[ItemPath]) {
+        self.items.retain(|item| {
+            prefixes.iter().any(|prefix| {
+                item.path == *prefix || item.path.is_inside(prefix) || prefix.is_inside(&item.path)
+            })
+        });
+    }
+}
+
 pub fn make_snapshot(
     driver: &dyn Driver,
+    side: Side,
     path: String,
-    ignore: &HashSet<&str>,
+    filters: &WalkFilters,
     stop_request: Arc<AtomicBool>,
-    on_item: Option<OnItemHandler>,
+    events: &Events,
 ) -> Result<Snapshot> {
-    let items = driver.find_all(&path, ignore, Arc::clone(&stop_request), on_item);
+    events.emit(Event::ScanStarted {
+        side,
+        path: path.clone(),
+    });
+
+    let on_item: OnItemHandler = {
+        let events = events.clone();
+        let counter = AtomicUsize::new(0);
+
+        Box::new(move |_| {
+            let items = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            events.emit(Event::ScanProgress { side, items });
+        })
+    };
+
+    let items = driver.find_all(&path, filters, Arc::clone(&stop_request), Some(on_item));
 
     // TODO: When https://github.com/rust-lang/rust/issues/91345 is resolved, use `inspect_err` instead of a match
     let items = match items {
         Ok(items) => items,
         Err(e) => {
             stop_request.store(true, Ordering::Relaxed);
+            events.emit(Event::Error(format!("{:#}", e)));
             return Err(e);
         }
     };
@@ -38,10 +73,17 @@ pub fn make_snapshot(
 
     for item in &items {
         if !uniq.insert(&item.path) {
-            bail!("Duplicate item in driver's results: {}", item.path);
+            let err = anyhow!("Duplicate item in driver's results: {}", item.path);
+            events.emit(Event::Error(err.to_string()));
+            return Err(err);
         }
     }
 
+    events.emit(Event::ScanFinished {
+        side,
+        items: items.len(),
+    });
+
     Ok(Snapshot { items, path })
 }
 
@@ -49,38 +91,198 @@ pub trait Driver {
     fn find_all(
         &self,
         dir: &str,
-        ignore: &HashSet<&str>,
+        filters: &WalkFilters,
         stop_request: Arc<AtomicBool>,
         on_item: Option<OnItemHandler>,
     ) -> Result<Vec<DriverItem>>;
+
+    /// Compute the checksum of a file's content, `path` being relative to `root`
+    fn checksum(&self, root: &str, path: &ItemPath) -> Result<[u8; 32]>;
+
+    /// Apply permissions and ownership to an item, `path` being relative to `root`
+    ///
+    /// Fields set to `None` are left untouched
+    fn set_permissions(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        permissions: &DriverItemPermissions,
+    ) -> Result<()>;
+
+    /// Indicate if the driver can read and write extended attributes
+    fn supports_xattrs(&self) -> bool {
+        false
+    }
+
+    /// Read the extended attributes of an item, `path` being relative to `root`
+    fn read_xattrs(&self, _root: &str, path: &ItemPath) -> Result<DriverItemXattrs> {
+        bail!(
+            "Driver does not support extended attributes (item: {})",
+            path
+        )
+    }
+
+    /// Replace the extended attributes of an item, `path` being relative to `root`
+    fn write_xattrs(&self, _root: &str, path: &ItemPath, _xattrs: &DriverItemXattrs) -> Result<()> {
+        bail!(
+            "Driver does not support extended attributes (item: {})",
+            path
+        )
+    }
+
+    /// Indicate if the driver can create hard links
+    fn supports_hard_links(&self) -> bool {
+        false
+    }
+
+    /// Make `path` a hard link to `target`, replacing `path` if it already exists
+    /// (both being relative to `root`)
+    fn hard_link(&self, _root: &str, _target: &ItemPath, path: &ItemPath) -> Result<()> {
+        bail!("Driver does not support hard links (item: {})", path)
+    }
+
+    /// Indicate if the driver can create named pipes and device nodes
+    fn supports_special(&self) -> bool {
+        false
+    }
+
+    /// Create a named pipe or device node, `path` being relative to `root`
+    fn create_special(
+        &self,
+        _root: &str,
+        path: &ItemPath,
+        _special: DriverSpecialMetadata,
+        _mode: u32,
+    ) -> Result<()> {
+        bail!("Driver does not support special files (item: {})", path)
+    }
+
+    /// Indicate if the driver can read and write file contents, create directories and remove items
+    fn supports_transfers(&self) -> bool {
+        false
+    }
+
+    /// Open a file for reading, `path` being relative to `root`
+    fn read_file(&self, _root: &str, path: &ItemPath) -> Result<Box<dyn Read + Send + '_>> {
+        bail!("Driver does not support reading files (item: {})", path)
+    }
+
+    /// Create or replace a file with the provided content and modification date,
+    /// `path` being relative to `root`
+    ///
+    /// A replaced file keeps its permissions, new files get the default ones
+    ///
+    /// Returns the number of bytes written
+    fn write_file(
+        &self,
+        _root: &str,
+        path: &ItemPath,
+        _content: &mut (dyn Read + Send),
+        _modification_date: i64,
+        _modification_date_nanos: Option<u32>,
+    ) -> Result<u64> {
+        bail!("Driver does not support writing files (item: {})", path)
+    }
+
+    /// Create a directory (its parent must exist), `path` being relative to `root`
+    fn create_dir(&self, _root: &str, path: &ItemPath) -> Result<()> {
+        bail!(
+            "Driver does not support creating directories (item: {})",
+            path
+        )
+    }
+
+    /// Remove a file, special file or empty directory, `path` being relative to `root`
+    fn remove(&self, _root: &str, path: &ItemPath, _is_dir: bool) -> Result<()> {
+        bail!("Driver does not support removing items (item: {})", path)
+    }
 }
 
 pub type OnItemHandler = Box<dyn Fn(&DriverItem) + Send + Sync + 'static>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DriverItem {
-    pub path: String,
+    pub path: ItemPath,
     pub metadata: DriverItemMetadata,
+    pub permissions: DriverItemPermissions,
+    /// Extended attributes, if the driver was asked to read them
+    pub xattrs: Option<DriverItemXattrs>,
+    /// Hard link group, for files with more than one link (if the driver supports it)
+    pub hard_link: Option<HardLinkId>,
+}
+
+/// Identifier shared by all hard links to the same file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HardLinkId {
+    pub device: u64,
+    pub inode: u64,
 }
 
+/// Extended attributes (including POSIX ACLs) of an item, by name
+pub type DriverItemXattrs = BTreeMap<OsString, Vec<u8>>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DriverItemMetadata {
     Directory,
     File(DriverFileMetadata),
+    Special(DriverSpecialMetadata),
 }
 
 impl DriverItemMetadata {
-    pub fn size(&self) -> Option<u64> {
+    pub fn size_with(&self, mode: SizeMode) -> Option<u64> {
         match self {
-            Self::Directory => None,
-            Self::File(m) => Some(m.size),
+            Self::Directory | Self::Special(_) => None,
+            Self::File(m) => Some(m.size_with(mode)),
         }
     }
 
     pub fn is_dir(&self) -> bool {
         match self {
             Self::Directory => true,
-            Self::File(_) => false,
+            Self::File(_) | Self::Special(_) => false,
+        }
+    }
+}
+
+/// Named pipes, sockets and device nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DriverSpecialMetadata {
+    Fifo,
+    Socket,
+    /// Device ID is only provided by some drivers
+    BlockDevice {
+        rdev: Option<u64>,
+    },
+    CharDevice {
+        rdev: Option<u64>,
+    },
+}
+
+impl DriverSpecialMetadata {
+    /// Check if two special items are of the same kind, device IDs being only compared
+    /// when both drivers provide them
+    pub fn is_same(&self, other: &Self) -> bool {
+        fn same_rdev(a: Option<u64>, b: Option<u64>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+
+        match (self, other) {
+            (Self::Fifo, Self::Fifo) | (Self::Socket, Self::Socket) => true,
+            (Self::BlockDevice { rdev: a }, Self::BlockDevice { rdev: b })
+            | (Self::CharDevice { rdev: a }, Self::CharDevice { rdev: b }) => same_rdev(*a, *b),
+            _ => false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fifo => "named pipe",
+            Self::Socket => "socket",
+            Self::BlockDevice { .. } => "block device",
+            Self::CharDevice { .. } => "character device",
         }
     }
 }
@@ -89,5 +291,63 @@ impl DriverItemMetadata {
 pub struct DriverFileMetadata {
     // pub creation_date: i64,
     pub modification_date: i64,
+    /// Sub-second part of the modification date, if the driver provides it
+    pub modification_date_nanos: Option<u32>,
+    /// Apparent size
     pub size: u64,
+    /// Size actually allocated on disk, if the driver provides it
+    pub allocated_size: Option<u64>,
+}
+
+impl DriverFileMetadata {
+    pub fn size_with(&self, mode: SizeMode) -> u64 {
+        match mode {
+            SizeMode::Apparent => self.size,
+            SizeMode::Allocated => self.allocated_size.unwrap_or(self.size),
+        }
+    }
+
+    /// Check if the file has holes (or is compressed by the filesystem)
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.allocated_size, Some(allocated) if allocated < self.size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeMode {
+    /// Size as reported by the file's length
+    #[default]
+    Apparent,
+
+    /// Size actually allocated on disk (falls back to the apparent size when not available)
+    Allocated,
+}
+
+impl FromStr for SizeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apparent" => Ok(Self::Apparent),
+            "allocated" => Ok(Self::Allocated),
+            _ => bail!(
+                "Unknown size mode '{}' (expected 'apparent' or 'allocated')",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DriverItemPermissions {
+    /// Permission bits (e.g. `0o755`), without the file type bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+
+    /// Name of the owner, when the driver can resolve it (only used for display)
+    pub user: Option<Arc<str>>,
+
+    /// Name of the group, when the driver can resolve it (only used for display)
+    pub group: Option<Arc<str>>,
 }
@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+
+use super::{DriverItemMetadata, ItemPath, Snapshot};
+
+/// Filters applied by drivers while walking a directory
+///
+/// Size and modification date filters only apply to files, directories are always kept.
+/// When comparing two directories, they should only be applied to the source's version of
+/// the files (see [`WalkFilters::location_only`]).
+#[derive(Debug, Clone, Default)]
+pub struct WalkFilters {
+    /// Names to ignore (along with their content for directories)
+    pub ignore: HashSet<String>,
+
+    /// Maximum depth of items, `1` being the root directory's direct children
+    pub max_depth: Option<usize>,
+
+    /// Minimum size of files, in bytes
+    pub min_size: Option<u64>,
+
+    /// Maximum size of files, in bytes
+    pub max_size: Option<u64>,
+
+    /// Only keep files modified at or after this timestamp
+    pub modified_after: Option<i64>,
+
+    /// Only keep files modified at or before this timestamp
+    pub modified_before: Option<i64>,
+}
+
+impl WalkFilters {
+    /// Check if an item at the provided depth should be kept
+    pub fn accepts_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth <= max_depth)
+    }
+
+    /// Check if the content of a directory at the provided depth should be walked
+    pub fn descends_into(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    /// Get the filters which only depend on the location of items (names and depth)
+    ///
+    /// Walking the destination with the size and modification date filters would leave out
+    /// copies of files which don't match them anymore, and so have them reported as deleted.
+    /// Both sides are walked with these filters instead, and the files rejected on the source
+    /// side (see [`WalkFilters::rejected_paths`]) are then removed from both snapshots.
+    pub fn location_only(&self) -> Self {
+        Self {
+            ignore: self.ignore.clone(),
+            max_depth: self.max_depth,
+            ..Default::default()
+        }
+    }
+
+    /// Get the paths of the files of a snapshot rejected by the size and modification date filters
+    pub fn rejected_paths(&self, snapshot: &Snapshot) -> HashSet<ItemPath> {
+        snapshot
+            .items
+            .iter()
+            .filter(|item| !self.accepts(&item.metadata))
+            .map(|item| item.path.clone())
+            .collect()
+    }
+
+    /// Check if an item should be kept, depending on its metadata
+    pub fn accepts(&self, metadata: &DriverItemMetadata) -> bool {
+        let DriverItemMetadata::File(file) = metadata else {
+            return true;
+        };
+
+        self.min_size.is_none_or(|min| file.size >= min)
+            && self.max_size.is_none_or(|max| file.size <= max)
+            && self
+                .modified_after
+                .is_none_or(|after| file.modification_date >= after)
+            && self
+                .modified_before
+                .is_none_or(|before| file.modification_date <= before)
+    }
+}
+
+impl ItemPath {
+    /// Get the depth of this path, `1` being the root directory's direct children
+    pub fn depth(&self) -> usize {
+        self.as_path().components().count()
+    }
+}
@@ -1,27 +1,106 @@
 use anyhow::Result;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
-    fs::canonicalize,
-    os::unix::prelude::MetadataExt,
-    path::Path,
+    fs::{self, canonicalize, File, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        fs::{chown, FileTypeExt, PermissionsExt},
+        prelude::MetadataExt,
+    },
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::{Duration, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context};
+use nix::{
+    sys::stat::{mknod, Mode, SFlag},
+    unistd::{mkfifo, Gid, Group, Uid, User},
+};
 use rayon::prelude::{ParallelBridge, ParallelIterator};
 use walkdir::WalkDir;
 
-use super::{Driver, DriverFileMetadata, DriverItem, DriverItemMetadata, OnItemHandler};
+use super::{
+    Driver, DriverFileMetadata, DriverItem, DriverItemMetadata, DriverItemPermissions,
+    DriverItemXattrs, DriverPools, DriverSpecialMetadata, HardLinkId, ItemPath, OnItemHandler,
+    Throttle, ThrottledReader, ThrottledWriter, WalkFilters,
+};
+
+/// Size of the blocks of zeros skipped when writing files, matching most filesystems' block size
+const SPARSE_BLOCK_SIZE: usize = 4096;
 
-pub struct FsDriver;
+/// Size of the buffer used when writing files
+const SPARSE_COPY_BUFFER_SIZE: usize = 64 * SPARSE_BLOCK_SIZE;
+
+pub struct FsDriver {
+    xattrs: bool,
+    one_file_system: bool,
+    included_mounts: Vec<PathBuf>,
+    excluded_paths: Vec<PathBuf>,
+    read_throttle: Option<Arc<Throttle>>,
+    write_throttle: Option<Arc<Throttle>>,
+    pools: DriverPools,
+}
 
 impl FsDriver {
     pub fn new() -> Self {
-        Self
+        Self {
+            xattrs: false,
+            one_file_system: false,
+            included_mounts: vec![],
+            excluded_paths: vec![],
+            read_throttle: None,
+            write_throttle: None,
+            pools: DriverPools::default(),
+        }
+    }
+
+    /// Read extended attributes (including POSIX ACLs) when building snapshots
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Don't descend into directories which are on another filesystem than the root
+    /// (mount points themselves are still listed)
+    pub fn with_one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Mount points to descend into anyway when staying on one filesystem
+    /// (those which aren't inside the walked directory, or whose parent isn't walked, are ignored)
+    pub fn with_included_mounts(mut self, included_mounts: Vec<PathBuf>) -> Self {
+        self.included_mounts = included_mounts;
+        self
+    }
+
+    /// Absolute paths to skip entirely (along with their content)
+    pub fn with_excluded_paths(mut self, excluded_paths: Vec<PathBuf>) -> Self {
+        self.excluded_paths = excluded_paths;
+        self
+    }
+
+    /// Limit the throughput of file contents read through this driver (including checksums)
+    pub fn with_read_throttle(mut self, throttle: Option<Arc<Throttle>>) -> Self {
+        self.read_throttle = throttle;
+        self
+    }
+
+    /// Limit the throughput of file contents written through this driver
+    pub fn with_write_throttle(mut self, throttle: Option<Arc<Throttle>>) -> Self {
+        self.write_throttle = throttle;
+        self
+    }
+
+    /// Use dedicated thread pools for scanning and transfers
+    pub fn with_pools(mut self, pools: DriverPools) -> Self {
+        self.pools = pools;
+        self
     }
 }
 
@@ -35,93 +114,666 @@ impl Driver for FsDriver {
     fn find_all(
         &self,
         root: &str,
-        ignore: &HashSet<&str>,
+        filters: &WalkFilters,
         stop_request: Arc<AtomicBool>,
         on_item: Option<OnItemHandler>,
     ) -> Result<Vec<DriverItem>> {
-        let ignore: HashSet<_> = ignore.iter().map(OsStr::new).collect();
+        let ignore: HashSet<_> = filters.ignore.iter().map(OsStr::new).collect();
+
+        let owner_names = OwnerNames::default();
 
         let root = canonicalize(root)
             .with_context(|| format!("Failed to canonicalize base directory at: {root}"))?;
 
-        let root = Path::new(root.to_str().with_context(|| {
-            format!(
-                "Base directory contains invalid UTF-8 characters: {}",
-                root.display()
-            )
-        })?);
+        let root = root.as_path();
 
         if !root.is_dir() {
             bail!("Root directory was not found!")
         }
 
-        WalkDir::new(root)
-            .min_depth(1)
-            .into_iter()
-            .filter_entry(|entry| {
-                !entry
-                    .path()
-                    .ancestors()
-                    .any(|ancestor| match ancestor.file_name() {
-                        Some(name) => ignore.contains(name),
-                        None => false,
+        let excluded_paths = self
+            .excluded_paths
+            .iter()
+            .map(|path| canonicalize(path).unwrap_or_else(|_| path.clone()))
+            .collect::<Vec<_>>();
+
+        let is_excluded = |path: &Path| {
+            excluded_paths
+                .iter()
+                .any(|excluded| path.starts_with(excluded))
+        };
+
+        // Included mount points are walked separately as the main walk won't descend into them
+        let mut walk_roots = vec![root.to_path_buf()];
+
+        if self.one_file_system {
+            let mut mounts = self
+                .included_mounts
+                .iter()
+                .map(|mount| {
+                    canonicalize(mount).with_context(|| {
+                        format!("Failed to canonicalize mount point at: {}", mount.display())
                     })
-            })
-            .par_bridge()
-            .map(|item| {
-                if stop_request.load(Ordering::Relaxed) {
-                    bail!("Process was requested to stop.");
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // Parents come first, so nested mount points can be checked against them
+            mounts.sort();
+            mounts.dedup();
+
+            for mount in mounts {
+                // Mount points outside of the root are simply not relevant to this walk
+                if !mount.starts_with(root) || mount == root || is_excluded(&mount) {
+                    continue;
                 }
 
-                let item = item.context("Failed to access item")?;
-                let item = item.path();
-                let metadata = item.metadata().with_context(|| {
-                    format!("Failed to get file's metadata for: {}", item.display())
-                })?;
+                // Directories on the same filesystem as their parent are already walked
+                if !is_mount_point(&mount)? {
+                    continue;
+                }
 
-                let path = get_relative_utf8_path(item, root)?.to_string();
+                // Nested mount points are only reachable if their parent directory is walked
+                let parent = mount.parent().unwrap_or(root);
+                let parent_dev = fs::metadata(parent)
+                    .with_context(|| format!("Failed to get metadata of: {}", parent.display()))?
+                    .dev();
 
-                let item = if metadata.is_symlink() {
-                    // TODO: symbolic links
-                    bail!("Warning: ignored symbolic link: {}", item.display())
-                } else if metadata.is_dir() {
-                    DriverItem {
-                        path,
-                        metadata: DriverItemMetadata::Directory,
+                let reachable = walk_roots.iter().any(|walk_root| {
+                    parent.starts_with(walk_root)
+                        && fs::metadata(walk_root).is_ok_and(|m| m.dev() == parent_dev)
+                });
+
+                if reachable {
+                    walk_roots.push(mount);
+                }
+            }
+        }
+
+        self.pools.scan(|| {
+            walk_roots
+                .iter()
+                .flat_map(|walk_root| {
+                    WalkDir::new(walk_root)
+                        .min_depth(1)
+                        .same_file_system(self.one_file_system)
+                        .into_iter()
+                        .filter_entry(|entry| {
+                            // Depth is relative to the root rather than to included mount points
+                            let depth = entry
+                                .path()
+                                .strip_prefix(root)
+                                .map_or(0, |path| path.components().count());
+
+                            filters.accepts_depth(depth)
+                                && !is_excluded(entry.path())
+                                && !entry.path().ancestors().any(|ancestor| {
+                                    match ancestor.file_name() {
+                                        Some(name) => ignore.contains(name),
+                                        None => false,
+                                    }
+                                })
+                        })
+                })
+                .par_bridge()
+                .map(|item| {
+                    if stop_request.load(Ordering::Relaxed) {
+                        bail!("Process was requested to stop.");
                     }
-                } else if metadata.is_file() {
-                    // TODO: get real size
-                    DriverItem {
-                        path,
-                        metadata: DriverItemMetadata::File(DriverFileMetadata {
+
+                    let item = item.context("Failed to access item")?;
+                    let item = item.path();
+                    let metadata = item.metadata().with_context(|| {
+                        format!("Failed to get file's metadata for: {}", item.display())
+                    })?;
+
+                    let path = get_relative_path(item, root)?;
+
+                    let item_metadata = if metadata.is_symlink() {
+                        // TODO: symbolic links
+                        bail!("Warning: ignored symbolic link: {}", item.display())
+                    } else if metadata.is_dir() {
+                        DriverItemMetadata::Directory
+                    } else if metadata.is_file() {
+                        DriverItemMetadata::File(DriverFileMetadata {
                             // creation_date: metadata.ctime(),
                             modification_date: metadata.mtime(),
+                            modification_date_nanos: Some(
+                                metadata.mtime_nsec().try_into().with_context(|| {
+                                    format!(
+                                        "Invalid modification time found for item: {}",
+                                        item.display()
+                                    )
+                                })?,
+                            ),
                             size: metadata.len(),
-                        }),
+                            // Blocks are always 512 bytes, regardless of the filesystem's block size
+                            allocated_size: Some(metadata.blocks() * 512),
+                        })
+                    } else if metadata.file_type().is_fifo() {
+                        DriverItemMetadata::Special(DriverSpecialMetadata::Fifo)
+                    } else if metadata.file_type().is_socket() {
+                        DriverItemMetadata::Special(DriverSpecialMetadata::Socket)
+                    } else if metadata.file_type().is_block_device() {
+                        DriverItemMetadata::Special(DriverSpecialMetadata::BlockDevice {
+                            rdev: Some(metadata.rdev()),
+                        })
+                    } else if metadata.file_type().is_char_device() {
+                        DriverItemMetadata::Special(DriverSpecialMetadata::CharDevice {
+                            rdev: Some(metadata.rdev()),
+                        })
+                    } else {
+                        bail!("Encountered unknown item type at: {}", item.display())
+                    };
+
+                    if !filters.accepts(&item_metadata) {
+                        return Ok(None);
+                    }
+
+                    let item = DriverItem {
+                        path,
+                        metadata: item_metadata,
+                        permissions: DriverItemPermissions {
+                            mode: Some(metadata.mode() & 0o7777),
+                            uid: Some(metadata.uid()),
+                            gid: Some(metadata.gid()),
+                            user: owner_names.user(metadata.uid()),
+                            group: owner_names.group(metadata.gid()),
+                        },
+                        xattrs: if self.xattrs {
+                            Some(read_xattrs(item)?)
+                        } else {
+                            None
+                        },
+                        hard_link: if metadata.is_file() && metadata.nlink() > 1 {
+                            Some(HardLinkId {
+                                device: metadata.dev(),
+                                inode: metadata.ino(),
+                            })
+                        } else {
+                            None
+                        },
+                    };
+
+                    if let Some(handler) = &on_item {
+                        handler(&item);
                     }
-                } else {
-                    bail!("Encountered unknown item type at: {}", item.display())
-                };
 
-                if let Some(handler) = &on_item {
-                    handler(&item);
+                    Ok(Some(item))
+                })
+                .filter_map(|r| r.transpose())
+                .collect::<Result<Vec<_>, _>>()
+        })
+    }
+
+    fn checksum(&self, root: &str, path: &ItemPath) -> Result<[u8; 32]> {
+        let path = Path::new(root).join(path.as_path());
+
+        self.pools.transfer(|| {
+            let file = File::open(&path)
+                .with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+            let mut hasher = blake3::Hasher::new();
+
+            io::copy(
+                &mut ThrottledReader::new(file, self.read_throttle.as_deref()),
+                &mut hasher,
+            )
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+
+            Ok(*hasher.finalize().as_bytes())
+        })
+    }
+
+    fn set_permissions(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        permissions: &DriverItemPermissions,
+    ) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        // Changing the owner clears the setuid and setgid bits, so it must happen first
+        if permissions.uid.is_some() || permissions.gid.is_some() {
+            chown(&path, permissions.uid, permissions.gid)
+                .with_context(|| format!("Failed to set ownership on: {}", path.display()))?;
+        }
+
+        if let Some(mode) = permissions.mode {
+            fs::set_permissions(&path, Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set permissions on: {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn supports_xattrs(&self) -> bool {
+        true
+    }
+
+    fn read_xattrs(&self, root: &str, path: &ItemPath) -> Result<DriverItemXattrs> {
+        read_xattrs(&Path::new(root).join(path.as_path()))
+    }
+
+    fn write_xattrs(&self, root: &str, path: &ItemPath, xattrs: &DriverItemXattrs) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        for name in read_xattrs(&path)?.keys() {
+            if !xattrs.contains_key(name) {
+                xattr::remove(&path, name).with_context(|| {
+                    format!(
+                        "Failed to remove extended attribute {:?} from: {}",
+                        name,
+                        path.display()
+                    )
+                })?;
+            }
+        }
+
+        for (name, value) in xattrs {
+            xattr::set(&path, name, value).with_context(|| {
+                format!(
+                    "Failed to set extended attribute {:?} on: {}",
+                    name,
+                    path.display()
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        true
+    }
+
+    fn hard_link(&self, root: &str, target: &ItemPath, path: &ItemPath) -> Result<()> {
+        let root = Path::new(root);
+        let target = root.join(target.as_path());
+        let path = root.join(path.as_path());
+
+        // Create the link under a temporary name first so an existing item is replaced atomically
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".differ-link");
+
+        fs::hard_link(&target, &tmp_path).with_context(|| {
+            format!(
+                "Failed to create hard link to {} at: {}",
+                target.display(),
+                path.display()
+            )
+        })?;
+
+        fs::rename(&tmp_path, &path).with_context(|| {
+            let _ = fs::remove_file(&tmp_path);
+            format!(
+                "Failed to replace item with hard link at: {}",
+                path.display()
+            )
+        })
+    }
+
+    fn supports_special(&self) -> bool {
+        true
+    }
+
+    fn create_special(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        special: DriverSpecialMetadata,
+        mode: u32,
+    ) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+        let mode = Mode::from_bits_truncate(mode);
+
+        let (kind, rdev) = match special {
+            DriverSpecialMetadata::Fifo => {
+                return mkfifo(&path, mode).with_context(|| {
+                    format!("Failed to create named pipe at: {}", path.display())
+                });
+            }
+            DriverSpecialMetadata::Socket => {
+                bail!("Sockets cannot be recreated (item: {})", path.display())
+            }
+            DriverSpecialMetadata::BlockDevice { rdev } => (SFlag::S_IFBLK, rdev),
+            DriverSpecialMetadata::CharDevice { rdev } => (SFlag::S_IFCHR, rdev),
+        };
+
+        let rdev = rdev.with_context(|| format!("Missing device ID for: {}", path.display()))?;
+
+        mknod(&path, kind, mode, rdev)
+            .with_context(|| format!("Failed to create device node at: {}", path.display()))
+    }
+
+    fn supports_transfers(&self) -> bool {
+        true
+    }
+
+    fn read_file(&self, root: &str, path: &ItemPath) -> Result<Box<dyn Read + Send + '_>> {
+        let path = Path::new(root).join(path.as_path());
+
+        let file = File::open(&path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+        Ok(Box::new(ThrottledReader::new(
+            file,
+            self.read_throttle.as_deref(),
+        )))
+    }
+
+    fn write_file(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        content: &mut (dyn Read + Send),
+        modification_date: i64,
+        modification_date_nanos: Option<u32>,
+    ) -> Result<u64> {
+        let path = Path::new(root).join(path.as_path());
+
+        // Write under a temporary name first so an existing file is replaced atomically
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".differ-tmp");
+
+        let mtime = UNIX_EPOCH
+            .checked_add(Duration::new(
+                modification_date.try_into().with_context(|| {
+                    format!(
+                        "Cannot set a modification date before 1970 on: {}",
+                        path.display()
+                    )
+                })?,
+                modification_date_nanos.unwrap_or(0),
+            ))
+            .with_context(|| format!("Invalid modification date for: {}", path.display()))?;
+
+        self.pools
+            .transfer(|| {
+                let file = File::create(&tmp_path)
+                    .with_context(|| format!("Failed to create file: {}", path.display()))?;
+
+                let written = copy_sparse(content, &file, self.write_throttle.as_deref())
+                    .with_context(|| format!("Failed to write file: {}", path.display()))?;
+
+                file.set_modified(mtime).with_context(|| {
+                    format!("Failed to set modification date on: {}", path.display())
+                })?;
+
+                // Keep the permissions of the file being replaced (e.g. its executable bit)
+                if let Ok(existing) = fs::symlink_metadata(&path) {
+                    file.set_permissions(Permissions::from_mode(existing.mode() & 0o7777))
+                        .with_context(|| {
+                            format!("Failed to set permissions on: {}", path.display())
+                        })?;
                 }
 
-                Ok(Some(item))
+                fs::rename(&tmp_path, &path)
+                    .with_context(|| format!("Failed to replace file at: {}", path.display()))?;
+
+                Ok(written)
+            })
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_path);
             })
-            .filter_map(|r| r.transpose())
-            .collect::<Result<Vec<_>, _>>()
+    }
+
+    fn create_dir(&self, root: &str, path: &ItemPath) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        fs::create_dir(&path)
+            .with_context(|| format!("Failed to create directory at: {}", path.display()))
+    }
+
+    fn remove(&self, root: &str, path: &ItemPath, is_dir: bool) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        if is_dir {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .with_context(|| format!("Failed to remove item at: {}", path.display()))
     }
 }
 
-fn get_relative_utf8_path<'a>(path: &'a Path, source: &Path) -> Result<&'a str> {
-    path.strip_prefix(source)
-        .context("Internal error: failed to strip prefix")?
-        .to_str()
-        .with_context(|| {
+/// Cache of the user and group names resolved during a walk, as most items share a few owners
+#[derive(Default)]
+struct OwnerNames {
+    users: Mutex<HashMap<u32, Option<Arc<str>>>>,
+    groups: Mutex<HashMap<u32, Option<Arc<str>>>>,
+}
+
+impl OwnerNames {
+    fn user(&self, uid: u32) -> Option<Arc<str>> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_insert_with(|| {
+                User::from_uid(Uid::from_raw(uid))
+                    .ok()
+                    .flatten()
+                    .map(|user| Arc::from(user.name))
+            })
+            .clone()
+    }
+
+    fn group(&self, gid: u32) -> Option<Arc<str>> {
+        self.groups
+            .lock()
+            .unwrap()
+            .entry(gid)
+            .or_insert_with(|| {
+                Group::from_gid(Gid::from_raw(gid))
+                    .ok()
+                    .flatten()
+                    .map(|group| Arc::from(group.name))
+            })
+            .clone()
+    }
+}
+
+fn is_mount_point(path: &Path) -> Result<bool> {
+    let parent = path
+        .parent()
+        .context("Internal error: mount point has no parent")?;
+
+    let device = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.dev())
+            .with_context(|| format!("Failed to get metadata for: {}", path.display()))
+    };
+
+    Ok(device(path)? != device(parent)?)
+}
+
+fn read_xattrs(path: &Path) -> Result<DriverItemXattrs> {
+    let mut xattrs = DriverItemXattrs::new();
+
+    let names = xattr::list(path)
+        .with_context(|| format!("Failed to list extended attributes of: {}", path.display()))?;
+
+    for name in names {
+        let value = xattr::get(path, &name).with_context(|| {
             format!(
-                "Item path contains invalid UTF-8 characters: {}",
+                "Failed to read extended attribute {:?} of: {}",
+                name,
                 path.display()
             )
-        })
+        })?;
+
+        // Attribute may have been removed in the meantime
+        if let Some(value) = value {
+            xattrs.insert(name, value);
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Copy a content to a new file, blocks of zeros being skipped instead of written
+/// so holes of sparse files aren't allocated on the destination
+fn copy_sparse(
+    content: &mut (dyn Read + Send),
+    mut file: &File,
+    throttle: Option<&Throttle>,
+) -> io::Result<u64> {
+    let mut writer = ThrottledWriter::new(file, throttle);
+    let mut buf = vec![0; SPARSE_COPY_BUFFER_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let read = read_full(content, &mut buf)?;
+
+        if read == 0 {
+            break;
+        }
+
+        // Contiguous data blocks are written at once
+        let mut data_start = 0;
+        let mut pos = 0;
+
+        for block in buf[..read].chunks(SPARSE_BLOCK_SIZE) {
+            if block.iter().all(|byte| *byte == 0) {
+                writer.write_all(&buf[data_start..pos])?;
+                file.seek(SeekFrom::Current(block.len() as i64))?;
+                data_start = pos + block.len();
+            }
+
+            pos += block.len();
+        }
+
+        writer.write_all(&buf[data_start..pos])?;
+        copied += read as u64;
+    }
+
+    writer.flush()?;
+
+    // Trailing holes are only created by setting the file's length
+    file.set_len(copied)?;
+
+    Ok(copied)
+}
+
+/// Fill a buffer from a reader, unless the end of its content is reached first
+fn read_full(reader: &mut (dyn Read + Send), buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
+
+fn get_relative_path(path: &Path, source: &Path) -> Result<ItemPath> {
+    path.strip_prefix(source)
+        .context("Internal error: failed to strip prefix")
+        .map(ItemPath::from_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temporary directory removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("differ-fs-{}-{}", name, std::process::id()));
+
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn root(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn set_permissions_keeps_setuid_bit() {
+        let dir = TempDir::new("setuid");
+        let path = dir.0.join("file");
+        fs::write(&path, "content").unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+
+        // Setting the current owner is enough to clear the bit if it is changed after the mode
+        let permissions = DriverItemPermissions {
+            mode: Some(0o4755),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            ..DriverItemPermissions::default()
+        };
+
+        FsDriver::new()
+            .set_permissions(dir.root(), &ItemPath::new(b"file".to_vec()), &permissions)
+            .unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, 0o4755);
+    }
+
+    /// Copy content with `copy_sparse`, returning the written file's content and allocated size
+    fn sparse_copy(dir: &TempDir, content: &[u8]) -> (Vec<u8>, u64) {
+        let path = dir.0.join("sparse");
+        let file = File::create(&path).unwrap();
+
+        let copied = copy_sparse(&mut io::Cursor::new(content), &file, None).unwrap();
+        assert_eq!(copied, content.len() as u64);
+        drop(file);
+
+        let allocated = fs::metadata(&path).unwrap().blocks() * 512;
+        (fs::read(&path).unwrap(), allocated)
+    }
+
+    #[test]
+    fn copy_sparse_keeps_holes_at_the_end() {
+        let dir = TempDir::new("sparse-end");
+
+        // Data followed by whole and partial blocks of zeros
+        let mut content = vec![1; 100];
+        content.resize(100 + SPARSE_BLOCK_SIZE * 64 + 10, 0);
+
+        let (written, allocated) = sparse_copy(&dir, &content);
+
+        assert_eq!(written, content);
+        assert!(
+            allocated < content.len() as u64,
+            "{} bytes allocated",
+            allocated
+        );
+    }
+
+    #[test]
+    fn copy_sparse_handles_files_made_of_holes() {
+        let dir = TempDir::new("sparse-holes");
+
+        let content = vec![0; SPARSE_COPY_BUFFER_SIZE * 2 + 1];
+
+        let (written, allocated) = sparse_copy(&dir, &content);
+
+        assert_eq!(written, content);
+        assert!(
+            allocated < content.len() as u64,
+            "{} bytes allocated",
+            allocated
+        );
+
+        let (written, _) = sparse_copy(&dir, &[]);
+        assert!(written.is_empty());
+    }
 }
@@ -0,0 +1,361 @@
+use std::{
+    collections::BTreeSet,
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{
+    Driver, DriverItem, DriverItemMetadata, DriverItemPermissions, DriverItemXattrs,
+    DriverSpecialMetadata, ItemPath, OnItemHandler, WalkFilters,
+};
+
+/// Source directory placed under a prefix of the merged namespace
+pub struct MergedRoot {
+    /// Path of the directory in the merged namespace (and so in the destination)
+    pub prefix: ItemPath,
+    pub driver: Box<dyn Driver + Send + Sync>,
+    pub dir: String,
+}
+
+/// Driver merging several directories, possibly from different drivers, into a single namespace
+/// mirroring the destination's layout
+///
+/// The `root` provided to the driver's methods is ignored, each directory having its own.
+/// Parent directories of the prefixes are listed as virtual directories without permissions.
+pub struct MergedDriver {
+    roots: Vec<MergedRoot>,
+}
+
+impl MergedDriver {
+    pub fn new(roots: Vec<MergedRoot>) -> Result<Self> {
+        if roots.is_empty() {
+            bail!("At least one directory must be mapped");
+        }
+
+        for root in &roots {
+            let prefix = root.prefix.as_bytes();
+
+            if prefix
+                .split(|byte| *byte == b'/')
+                .any(|name| name.is_empty() || name == b"." || name == b"..")
+            {
+                bail!(
+                    "Invalid prefix '{}' for mapped directory '{}' (expected a relative path without '.' or '..')",
+                    root.prefix,
+                    root.dir
+                );
+            }
+        }
+
+        for (i, root) in roots.iter().enumerate() {
+            for other in &roots[i + 1..] {
+                if root.prefix == other.prefix
+                    || root.prefix.is_inside(&other.prefix)
+                    || other.prefix.is_inside(&root.prefix)
+                {
+                    bail!(
+                        "Mapped directories '{}' and '{}' overlap in the destination ('{}' and '{}')",
+                        root.dir,
+                        other.dir,
+                        root.prefix,
+                        other.prefix
+                    );
+                }
+            }
+        }
+
+        Ok(Self { roots })
+    }
+
+    /// Get the prefix of each mapped directory
+    pub fn prefixes(&self) -> Vec<ItemPath> {
+        self.roots.iter().map(|root| root.prefix.clone()).collect()
+    }
+
+    /// Find the mapped directory an item belongs to, along with its path relative to it
+    fn route(&self, path: &ItemPath) -> Result<(&MergedRoot, ItemPath)> {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(&root.prefix).map(|path| (root, path)))
+            .with_context(|| format!("Item is not inside a mapped directory: {}", path))
+    }
+}
+
+impl Driver for MergedDriver {
+    fn find_all(
+        &self,
+        _dir: &str,
+        filters: &WalkFilters,
+        stop_request: Arc<AtomicBool>,
+        on_item: Option<OnItemHandler>,
+    ) -> Result<Vec<DriverItem>> {
+        let on_item = on_item.map(Arc::new);
+
+        // Directories are walked in parallel, the first failure stopping the others
+        let found = thread::scope(|s| {
+            let handles = self
+                .roots
+                .iter()
+                .map(|root| {
+                    let stop_request = Arc::clone(&stop_request);
+
+                    // Depth is counted from the merged namespace's root, above the prefix
+                    let prefix_depth = root.prefix.depth();
+
+                    let filters = WalkFilters {
+                        max_depth: filters
+                            .max_depth
+                            .map(|max_depth| max_depth.saturating_sub(prefix_depth)),
+                        ..filters.clone()
+                    };
+
+                    // Items are reported with their path in the merged namespace
+                    let on_item = on_item.clone().map(|on_item| -> OnItemHandler {
+                        let prefix = root.prefix.clone();
+
+                        Box::new(move |item| {
+                            on_item(&DriverItem {
+                                path: prefix.join(&item.path),
+                                ..item.clone()
+                            })
+                        })
+                    });
+
+                    s.spawn(move || {
+                        // The prefix itself is already as deep as allowed
+                        if filters.max_depth == Some(0) {
+                            return Ok(vec![]);
+                        }
+
+                        root.driver
+                            .find_all(&root.dir, &filters, Arc::clone(&stop_request), on_item)
+                            .inspect_err(|_| stop_request.store(true, Ordering::Relaxed))
+                            .with_context(|| {
+                                format!("Failed to walk mapped directory: {}", root.dir)
+                            })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("Internal error: a thread panicked")))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut virtual_dirs = BTreeSet::new();
+
+        for root in &self.roots {
+            let prefix = root.prefix.as_bytes();
+
+            for (i, _) in prefix.iter().enumerate().filter(|(_, byte)| **byte == b'/') {
+                virtual_dirs.insert(ItemPath::new(prefix[..i].to_vec()));
+            }
+
+            virtual_dirs.insert(root.prefix.clone());
+        }
+
+        virtual_dirs.retain(|dir| filters.accepts_depth(dir.depth()));
+
+        let mut items = virtual_dirs
+            .into_iter()
+            .map(|path| DriverItem {
+                path,
+                metadata: DriverItemMetadata::Directory,
+                permissions: DriverItemPermissions::default(),
+                xattrs: None,
+                hard_link: None,
+            })
+            .collect::<Vec<_>>();
+
+        for (root, found) in self.roots.iter().zip(found) {
+            items.extend(found?.into_iter().map(|item| DriverItem {
+                path: root.prefix.join(&item.path),
+                ..item
+            }));
+        }
+
+        Ok(items)
+    }
+
+    fn checksum(&self, _root: &str, path: &ItemPath) -> Result<[u8; 32]> {
+        let (root, path) = self.route(path)?;
+        root.driver.checksum(&root.dir, &path)
+    }
+
+    fn set_permissions(
+        &self,
+        _root: &str,
+        path: &ItemPath,
+        permissions: &DriverItemPermissions,
+    ) -> Result<()> {
+        let (root, path) = self.route(path)?;
+        root.driver.set_permissions(&root.dir, &path, permissions)
+    }
+
+    fn supports_xattrs(&self) -> bool {
+        self.roots.iter().all(|root| root.driver.supports_xattrs())
+    }
+
+    fn read_xattrs(&self, _root: &str, path: &ItemPath) -> Result<DriverItemXattrs> {
+        let (root, path) = self.route(path)?;
+        root.driver.read_xattrs(&root.dir, &path)
+    }
+
+    fn write_xattrs(&self, _root: &str, path: &ItemPath, xattrs: &DriverItemXattrs) -> Result<()> {
+        let (root, path) = self.route(path)?;
+        root.driver.write_xattrs(&root.dir, &path, xattrs)
+    }
+
+    fn supports_hard_links(&self) -> bool {
+        self.roots
+            .iter()
+            .all(|root| root.driver.supports_hard_links())
+    }
+
+    fn hard_link(&self, _root: &str, target: &ItemPath, path: &ItemPath) -> Result<()> {
+        let (root, link) = self.route(path)?;
+
+        let Some(target) = target.strip_prefix(&root.prefix) else {
+            bail!(
+                "Cannot link {} to {} as they are in different mapped directories",
+                path,
+                target
+            );
+        };
+
+        root.driver.hard_link(&root.dir, &target, &link)
+    }
+
+    fn supports_special(&self) -> bool {
+        self.roots.iter().all(|root| root.driver.supports_special())
+    }
+
+    fn create_special(
+        &self,
+        _root: &str,
+        path: &ItemPath,
+        special: DriverSpecialMetadata,
+        mode: u32,
+    ) -> Result<()> {
+        let (root, path) = self.route(path)?;
+        root.driver.create_special(&root.dir, &path, special, mode)
+    }
+
+    fn supports_transfers(&self) -> bool {
+        self.roots
+            .iter()
+            .all(|root| root.driver.supports_transfers())
+    }
+
+    fn read_file(&self, _root: &str, path: &ItemPath) -> Result<Box<dyn Read + Send + '_>> {
+        let (root, path) = self.route(path)?;
+        root.driver.read_file(&root.dir, &path)
+    }
+
+    fn write_file(
+        &self,
+        _root: &str,
+        path: &ItemPath,
+        content: &mut (dyn Read + Send),
+        modification_date: i64,
+        modification_date_nanos: Option<u32>,
+    ) -> Result<u64> {
+        let (root, path) = self.route(path)?;
+
+        root.driver.write_file(
+            &root.dir,
+            &path,
+            content,
+            modification_date,
+            modification_date_nanos,
+        )
+    }
+
+    fn create_dir(&self, _root: &str, path: &ItemPath) -> Result<()> {
+        let (root, path) = self.route(path)?;
+        root.driver.create_dir(&root.dir, &path)
+    }
+
+    fn remove(&self, _root: &str, path: &ItemPath, is_dir: bool) -> Result<()> {
+        let (root, path) = self.route(path)?;
+        root.driver.remove(&root.dir, &path, is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::drivers::fs::FsDriver;
+
+    #[test]
+    fn max_depth_is_counted_from_the_merged_root() {
+        let dir = std::env::temp_dir().join(format!("differ-merged-{}", std::process::id()));
+
+        for path in ["docs/sub/deep", "nested/sub"] {
+            fs::create_dir_all(dir.join(path)).unwrap();
+        }
+
+        fs::write(dir.join("docs/x"), "x").unwrap();
+        fs::write(dir.join("nested/y"), "y").unwrap();
+
+        let root = |prefix: &str, source: &str| MergedRoot {
+            prefix: ItemPath::new(prefix.as_bytes().to_vec()),
+            driver: Box::new(FsDriver::new()),
+            dir: dir.join(source).to_str().unwrap().to_string(),
+        };
+
+        let driver = MergedDriver::new(vec![root("docs", "docs"), root("a/b", "nested")]).unwrap();
+
+        let find_all = |max_depth| {
+            let filters = WalkFilters {
+                max_depth,
+                ..WalkFilters::default()
+            };
+
+            let mut paths = driver
+                .find_all("", &filters, Arc::new(AtomicBool::new(false)), None)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.path.to_string())
+                .collect::<Vec<_>>();
+
+            paths.sort();
+            paths
+        };
+
+        let all = find_all(None);
+        let limited = find_all(Some(2));
+        let shallow = find_all(Some(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            all,
+            [
+                "a",
+                "a/b",
+                "a/b/sub",
+                "a/b/y",
+                "docs",
+                "docs/sub",
+                "docs/sub/deep",
+                "docs/x"
+            ]
+        );
+        assert_eq!(limited, ["a", "a/b", "docs", "docs/sub", "docs/x"]);
+        assert_eq!(shallow, ["a", "docs"]);
+    }
+}
@@ -1,5 +1,15 @@
 mod common;
+mod filters;
 pub mod fs;
+pub mod merged;
+mod path;
+mod persist;
+mod pools;
 pub mod sftp;
+mod throttle;
 
 pub use common::*;
+pub use filters::*;
+pub use path::*;
+pub use pools::*;
+pub use throttle::*;
@@ -0,0 +1,79 @@
+use std::{
+    ffi::OsStr,
+    fmt::{self, Display, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    str,
+};
+
+/// Path of an item relative to the snapshot's root, stored as raw bytes
+/// so names which aren't valid UTF-8 are kept (and compared) exactly
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ItemPath(Vec<u8>);
+
+impl ItemPath {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn from_path(path: &Path) -> Self {
+        Self(path.as_os_str().as_bytes().to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.0))
+    }
+
+    /// Check if this path is strictly inside the `dir` directory
+    pub fn is_inside(&self, dir: &ItemPath) -> bool {
+        let (path, dir) = (&self.0, &dir.0);
+
+        path.len() > dir.len() && path.starts_with(dir) && path[dir.len()] == b'/'
+    }
+
+    /// Get the path relative to the `dir` directory, if it is strictly inside it
+    pub fn strip_prefix(&self, dir: &ItemPath) -> Option<ItemPath> {
+        self.is_inside(dir)
+            .then(|| Self(self.0[dir.0.len() + 1..].to_vec()))
+    }
+
+    /// Get the path of an item relative to this directory
+    pub fn join(&self, path: &ItemPath) -> ItemPath {
+        let mut joined = Vec::with_capacity(self.0.len() + 1 + path.0.len());
+        joined.extend_from_slice(&self.0);
+        joined.push(b'/');
+        joined.extend_from_slice(&path.0);
+
+        Self(joined)
+    }
+
+    /// Get the path as a string, if it is valid UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+}
+
+/// Lossless display: invalid UTF-8 bytes are escaped as `\xNN`, and backslashes as `\\`
+impl Display for ItemPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if c == '\\' {
+                    f.write_str("\\\\")?;
+                } else {
+                    f.write_char(c)?;
+                }
+            }
+
+            for byte in chunk.invalid() {
+                write!(f, "\\x{:02X}", byte)?;
+            }
+        }
+
+        Ok(())
+    }
+}
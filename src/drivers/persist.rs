@@ -0,0 +1,365 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    DriverFileMetadata, DriverItem, DriverItemMetadata, DriverItemPermissions,
+    DriverSpecialMetadata, ItemPath, Snapshot,
+};
+
+/// First line of a saved snapshot, to detect unrelated files and format changes
+const HEADER: &str = "differ-snapshot 1";
+
+/// Snapshots are saved as text, one tab-separated line per item:
+///
+/// `<type> <mtime> <nanos> <size> <allocated size> <mode> <uid> <gid> <path>`
+///
+/// Missing values are written as `-`. The type is `d` (directory), `f` (file), `p` (named pipe),
+/// `s` (socket), `b:<rdev>` (block device) or `c:<rdev>` (character device).
+/// Extended attributes, hard links and user/group names are not saved.
+impl Snapshot {
+    /// Save the snapshot to a file (replaced atomically if it already exists)
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let write = || -> Result<()> {
+            let mut file = BufWriter::new(File::create(&tmp_path)?);
+
+            writeln!(file, "{}", HEADER)?;
+            writeln!(file, "{}", escape(self.path.as_bytes()))?;
+
+            for item in &self.items {
+                writeln!(file, "{}", serialize_item(item))?;
+            }
+
+            file.into_inner()?.sync_all()?;
+            fs::rename(&tmp_path, path)?;
+
+            Ok(())
+        };
+
+        write().with_context(|| {
+            let _ = fs::remove_file(&tmp_path);
+            format!("Failed to save snapshot to: {}", path.display())
+        })
+    }
+
+    /// Load a snapshot saved with [`Snapshot::save`]
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open snapshot file: {}", path.display()))?;
+
+        let mut lines = BufReader::new(file).lines();
+
+        let mut next_line = || -> Result<Option<String>> {
+            lines
+                .next()
+                .transpose()
+                .with_context(|| format!("Failed to read snapshot file: {}", path.display()))
+        };
+
+        if next_line()?.as_deref() != Some(HEADER) {
+            bail!(
+                "Not a snapshot file (or unsupported version): {}",
+                path.display()
+            );
+        }
+
+        let root = next_line()?
+            .with_context(|| format!("Snapshot file is truncated: {}", path.display()))?;
+
+        let root = String::from_utf8(unescape(&root)?)
+            .with_context(|| format!("Invalid root path in snapshot file: {}", path.display()))?;
+
+        let mut items = vec![];
+        let mut line_number = 2;
+
+        while let Some(line) = next_line()? {
+            line_number += 1;
+
+            items.push(parse_item(&line).with_context(|| {
+                format!(
+                    "Invalid item at line {} of snapshot file: {}",
+                    line_number,
+                    path.display()
+                )
+            })?);
+        }
+
+        Ok(Self { path: root, items })
+    }
+}
+
+fn serialize_item(item: &DriverItem) -> String {
+    fn opt<T: ToString>(value: Option<T>) -> String {
+        value.map_or_else(|| "-".to_string(), |value| value.to_string())
+    }
+
+    let (item_type, file) = match item.metadata {
+        DriverItemMetadata::Directory => ("d".to_string(), None),
+        DriverItemMetadata::File(m) => ("f".to_string(), Some(m)),
+        DriverItemMetadata::Special(special) => (
+            match special {
+                DriverSpecialMetadata::Fifo => "p".to_string(),
+                DriverSpecialMetadata::Socket => "s".to_string(),
+                DriverSpecialMetadata::BlockDevice { rdev } => format!("b:{}", opt(rdev)),
+                DriverSpecialMetadata::CharDevice { rdev } => format!("c:{}", opt(rdev)),
+            },
+            None,
+        ),
+    };
+
+    let mut line = String::new();
+
+    write!(
+        line,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        item_type,
+        opt(file.map(|m| m.modification_date)),
+        opt(file.and_then(|m| m.modification_date_nanos)),
+        opt(file.map(|m| m.size)),
+        opt(file.and_then(|m| m.allocated_size)),
+        opt(item.permissions.mode.map(|mode| format!("{:o}", mode))),
+        opt(item.permissions.uid),
+        opt(item.permissions.gid),
+        escape(item.path.as_bytes())
+    )
+    .unwrap();
+
+    line
+}
+
+fn parse_item(line: &str) -> Result<DriverItem> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+
+    let [item_type, mtime, nanos, size, allocated_size, mode, uid, gid, path] = fields[..] else {
+        bail!("Expected 9 fields, found {}", fields.len());
+    };
+
+    fn opt<T: FromStr>(value: &str) -> Result<Option<T>>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match value {
+            "-" => Ok(None),
+            value => value
+                .parse()
+                .map(Some)
+                .with_context(|| format!("Invalid value: {}", value)),
+        }
+    }
+
+    let metadata = match item_type {
+        "d" => DriverItemMetadata::Directory,
+        "f" => DriverItemMetadata::File(DriverFileMetadata {
+            modification_date: opt(mtime)?.context("Missing modification date")?,
+            modification_date_nanos: opt(nanos)?,
+            size: opt(size)?.context("Missing size")?,
+            allocated_size: opt(allocated_size)?,
+        }),
+        "p" => DriverItemMetadata::Special(DriverSpecialMetadata::Fifo),
+        "s" => DriverItemMetadata::Special(DriverSpecialMetadata::Socket),
+        _ => match item_type.split_once(':') {
+            Some(("b", rdev)) => {
+                DriverItemMetadata::Special(DriverSpecialMetadata::BlockDevice { rdev: opt(rdev)? })
+            }
+            Some(("c", rdev)) => {
+                DriverItemMetadata::Special(DriverSpecialMetadata::CharDevice { rdev: opt(rdev)? })
+            }
+            _ => bail!("Unknown item type: {}", item_type),
+        },
+    };
+
+    let mode = match mode {
+        "-" => None,
+        mode => {
+            Some(u32::from_str_radix(mode, 8).with_context(|| format!("Invalid mode: {}", mode))?)
+        }
+    };
+
+    Ok(DriverItem {
+        path: ItemPath::new(unescape(path)?),
+        metadata,
+        permissions: DriverItemPermissions {
+            mode,
+            uid: opt(uid)?,
+            gid: opt(gid)?,
+            user: None,
+            group: None,
+        },
+        xattrs: None,
+        hard_link: None,
+    })
+}
+
+/// Escape separators, backslashes and invalid UTF-8 bytes so any path fits in a single field
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c => escaped.push(c),
+            }
+        }
+
+        for byte in chunk.invalid() {
+            write!(escaped, "\\x{:02X}", byte).unwrap();
+        }
+    }
+
+    escaped
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+
+        let Some((&escaped, tail)) = rest.split_first() else {
+            bail!("Unterminated escape sequence in: {}", s);
+        };
+
+        rest = tail;
+
+        match escaped {
+            b'\\' => bytes.push(b'\\'),
+            b't' => bytes.push(b'\t'),
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b'x' if rest.len() >= 2 => {
+                let hex = std::str::from_utf8(&rest[..2])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .with_context(|| format!("Invalid escape sequence in: {}", s))?;
+
+                bytes.push(hex);
+                rest = &rest[2..];
+            }
+            _ => bail!("Invalid escape sequence in: {}", s),
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(path: &[u8], metadata: DriverItemMetadata) -> DriverItem {
+        DriverItem {
+            path: ItemPath::new(path.to_vec()),
+            metadata,
+            permissions: DriverItemPermissions {
+                mode: Some(0o755),
+                uid: Some(1000),
+                gid: None,
+                user: None,
+                group: None,
+            },
+            xattrs: None,
+            hard_link: None,
+        }
+    }
+
+    fn assert_round_trip(item: &DriverItem) {
+        let parsed = parse_item(&serialize_item(item)).unwrap();
+
+        assert_eq!(parsed.path, item.path);
+        assert_eq!(parsed.metadata, item.metadata);
+        assert_eq!(parsed.permissions, item.permissions);
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let names: [&[u8]; 6] = [
+            b"plain.txt",
+            b"tab\there",
+            b"new\nline\r",
+            b"back\\slash\\t",
+            b"invalid \xFF\xFE utf-8",
+            "accentu\u{e9}".as_bytes(),
+        ];
+
+        for name in names {
+            let escaped = escape(name);
+
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{:?}", escaped);
+            assert_eq!(unescape(&escaped).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn unescape_rejects_invalid_sequences() {
+        assert!(unescape("trailing\\").is_err());
+        assert!(unescape("\\q").is_err());
+        assert!(unescape("\\xZZ").is_err());
+        assert!(unescape("\\x4").is_err());
+    }
+
+    #[test]
+    fn item_round_trip() {
+        let file = DriverItemMetadata::File(DriverFileMetadata {
+            modification_date: -42,
+            modification_date_nanos: Some(123),
+            size: 4096,
+            allocated_size: None,
+        });
+
+        assert_round_trip(&item(b"dir\twith tab/file\n\xFF", file));
+        assert_round_trip(&item(b"dir", DriverItemMetadata::Directory));
+
+        for special in [
+            DriverSpecialMetadata::Fifo,
+            DriverSpecialMetadata::Socket,
+            DriverSpecialMetadata::BlockDevice { rdev: Some(2049) },
+            DriverSpecialMetadata::CharDevice { rdev: None },
+        ] {
+            assert_round_trip(&item(b"dev/node", DriverItemMetadata::Special(special)));
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("differ-snapshot-{}", std::process::id()));
+
+        let snapshot = Snapshot {
+            path: "/root\twith\ttabs".to_string(),
+            items: vec![
+                item(b"a\nb", DriverItemMetadata::Directory),
+                item(b"a\nb/\x80", DriverItemMetadata::Directory),
+            ],
+        };
+
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.path, snapshot.path);
+        assert_eq!(loaded.items.len(), snapshot.items.len());
+
+        for (loaded, item) in loaded.items.iter().zip(&snapshot.items) {
+            assert_eq!(loaded.path, item.path);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// Dedicated thread pools of a driver, rayon's global pool being used for those which aren't set
+#[derive(Clone, Default)]
+pub struct DriverPools {
+    scan: Option<Arc<ThreadPool>>,
+    transfer: Option<Arc<ThreadPool>>,
+}
+
+impl DriverPools {
+    /// Create pools with the provided number of threads for scanning directories
+    /// and for reading or writing file contents
+    pub fn new(scan_threads: Option<usize>, transfer_threads: Option<usize>) -> Result<Self> {
+        let build = |threads: Option<usize>, name: &'static str| -> Result<_> {
+            threads
+                .map(|threads| {
+                    ThreadPoolBuilder::new()
+                        .num_threads(threads)
+                        .thread_name(move |i| format!("{}-{}", name, i))
+                        .build()
+                        .map(Arc::new)
+                        .with_context(|| format!("Failed to create {} thread pool", name))
+                })
+                .transpose()
+        };
+
+        Ok(Self {
+            scan: build(scan_threads, "scan")?,
+            transfer: build(transfer_threads, "transfer")?,
+        })
+    }
+
+    /// Run an operation in the scan pool
+    pub fn scan<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.scan {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// Spawn a task in the scan pool
+    pub fn spawn_scan(&self, op: impl FnOnce() + Send + 'static) {
+        match &self.scan {
+            Some(pool) => pool.spawn(op),
+            None => rayon::spawn(op),
+        }
+    }
+
+    /// Run an operation in the transfer pool, which bounds the number of concurrent ones
+    pub fn transfer<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.transfer {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
     collections::HashSet,
     convert::TryInto,
+    ffi::{OsStr, OsString},
+    io::{self, Read, Write},
     net::TcpStream,
     path::{Path, PathBuf},
     sync::{
@@ -11,12 +13,19 @@ use std::{
 };
 
 use anyhow::{bail, Context, Result};
-use ssh2::{Session, Sftp};
+use ssh2::{FileStat, FileType, RenameFlags, Session, Sftp};
 
-use super::{Driver, DriverFileMetadata, DriverItem, DriverItemMetadata, OnItemHandler};
+use super::{
+    Driver, DriverFileMetadata, DriverItem, DriverItemMetadata, DriverItemPermissions, DriverPools,
+    DriverSpecialMetadata, ItemPath, OnItemHandler, Throttle, ThrottledReader, ThrottledWriter,
+    WalkFilters,
+};
 
 pub struct SftpDriver {
     sftp: Arc<Sftp>,
+    read_throttle: Option<Arc<Throttle>>,
+    write_throttle: Option<Arc<Throttle>>,
+    pools: DriverPools,
 }
 
 impl SftpDriver {
@@ -26,46 +35,78 @@ impl SftpDriver {
         pub_key_file: &Path,
         priv_key_file: &Path,
     ) -> Result<Self> {
-        let tcp = TcpStream::connect(address)?;
+        let tcp = TcpStream::connect(address)
+            .with_context(|| format!("Failed to connect to: {}", address))?;
 
-        let mut session = Session::new().unwrap();
+        let mut session = Session::new().context("Failed to create SSH session")?;
         session.set_tcp_stream(tcp);
-        session.handshake().unwrap();
+        session
+            .handshake()
+            .with_context(|| format!("SSH handshake with {} failed", address))?;
 
-        session.userauth_pubkey_file(username, Some(pub_key_file), priv_key_file, None)?;
+        session
+            .userauth_pubkey_file(username, Some(pub_key_file), priv_key_file, None)
+            .with_context(|| format!("Failed to authenticate as {} on {}", username, address))?;
 
         if !session.authenticated() {
             bail!("Session is not authenticated!");
         }
 
-        let sftp = session.sftp().unwrap();
+        let sftp = session
+            .sftp()
+            .with_context(|| format!("Failed to start SFTP session on: {}", address))?;
 
         Ok(Self {
             sftp: Arc::new(sftp),
+            read_throttle: None,
+            write_throttle: None,
+            pools: DriverPools::default(),
         })
     }
+
+    /// Limit the throughput of file contents read through this driver (including checksums)
+    pub fn with_read_throttle(mut self, throttle: Option<Arc<Throttle>>) -> Self {
+        self.read_throttle = throttle;
+        self
+    }
+
+    /// Limit the throughput of file contents written through this driver
+    pub fn with_write_throttle(mut self, throttle: Option<Arc<Throttle>>) -> Self {
+        self.write_throttle = throttle;
+        self
+    }
+
+    /// Use dedicated thread pools for scanning and transfers
+    pub fn with_pools(mut self, pools: DriverPools) -> Self {
+        self.pools = pools;
+        self
+    }
 }
 
 impl Driver for SftpDriver {
     fn find_all(
         &self,
         root: &str,
-        ignore: &HashSet<&str>,
+        filters: &WalkFilters,
         stop_request: Arc<AtomicBool>,
         on_item: Option<OnItemHandler>,
     ) -> Result<Vec<DriverItem>> {
         let root = Path::new(root);
         let dirs_contents = Arc::new(Mutex::new(vec![]));
-        let remaining = Arc::new(AtomicU32::new(1));
+        let remaining = Arc::new(AtomicU32::new(0));
+        let error = Arc::new(Mutex::new(None));
 
         let state = ReadDirState {
             sftp: Arc::clone(&self.sftp),
-            ignore: Arc::new(ignore.iter().map(|val| val.to_string()).collect()),
+            ignore: Arc::new(filters.ignore.iter().map(OsString::from).collect()),
+            filters: Arc::new(filters.clone()),
+            pools: self.pools.clone(),
             root: Arc::new(root.to_path_buf()),
             stop_request,
             on_item: Arc::new(on_item),
             dirs_contents: Arc::clone(&dirs_contents),
             remaining: Arc::clone(&remaining),
+            error: Arc::clone(&error),
         };
 
         let root_bis = root.to_path_buf();
@@ -76,53 +117,243 @@ impl Driver for SftpDriver {
             std::thread::sleep(Duration::from_millis(100));
         }
 
-        // TODO: handle these .unwrap() as errors
-        Ok(Arc::try_unwrap(dirs_contents)
-            .unwrap()
-            .into_inner()
-            .unwrap())
+        if let Some(err) = error.lock().unwrap().take() {
+            return Err(err);
+        }
+
+        let items = std::mem::take(&mut *dirs_contents.lock().unwrap());
+
+        Ok(items)
     }
-}
 
-fn get_relative_utf8_path<'a>(path: &'a Path, source: &Path) -> Result<&'a str> {
-    path.strip_prefix(source)
-        .context("Internal error: failed to strip prefix")?
-        .to_str()
-        .with_context(|| {
-            format!(
-                "Item path contains invalid UTF-8 characters: {}",
-                path.display()
+    fn checksum(&self, root: &str, path: &ItemPath) -> Result<[u8; 32]> {
+        let path = Path::new(root).join(path.as_path());
+
+        self.pools.transfer(|| {
+            let file = self
+                .sftp
+                .open(&path)
+                .with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+            let mut hasher = blake3::Hasher::new();
+
+            io::copy(
+                &mut ThrottledReader::new(file, self.read_throttle.as_deref()),
+                &mut hasher,
             )
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+
+            Ok(*hasher.finalize().as_bytes())
         })
-}
+    }
 
-fn get_filename(path: &Path) -> Result<&str> {
-    path.file_name()
-        .with_context(|| format!("Filename is missing on path: {}", path.display()))?
-        .to_str()
-        .with_context(|| {
+    fn set_permissions(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        permissions: &DriverItemPermissions,
+    ) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        // Owner and group can only be changed together
+        let (uid, gid) = match (permissions.uid, permissions.gid) {
+            (Some(_), None) | (None, Some(_)) => {
+                let current = self.sftp.stat(&path).with_context(|| {
+                    format!("Failed to get item's metadata for: {}", path.display())
+                })?;
+
+                (
+                    permissions.uid.or(current.uid),
+                    permissions.gid.or(current.gid),
+                )
+            }
+            (uid, gid) => (uid, gid),
+        };
+
+        let set = |uid, gid, perm| {
+            let stat = FileStat {
+                size: None,
+                uid,
+                gid,
+                perm,
+                atime: None,
+                mtime: None,
+            };
+
+            self.sftp
+                .setstat(&path, stat)
+                .with_context(|| format!("Failed to set permissions on: {}", path.display()))
+        };
+
+        // Servers usually change the mode before the owner, which clears the setuid and setgid
+        // bits, so the owner is changed separately first
+        if (uid.is_some() || gid.is_some()) && permissions.mode.is_some() {
+            set(uid, gid, None)?;
+            return set(None, None, permissions.mode);
+        }
+
+        set(uid, gid, permissions.mode)
+    }
+
+    fn supports_transfers(&self) -> bool {
+        true
+    }
+
+    fn read_file(&self, root: &str, path: &ItemPath) -> Result<Box<dyn Read + Send + '_>> {
+        let path = Path::new(root).join(path.as_path());
+
+        let file = self
+            .sftp
+            .open(&path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+        Ok(Box::new(ThrottledReader::new(
+            file,
+            self.read_throttle.as_deref(),
+        )))
+    }
+
+    fn write_file(
+        &self,
+        root: &str,
+        path: &ItemPath,
+        content: &mut (dyn Read + Send),
+        modification_date: i64,
+        _modification_date_nanos: Option<u32>,
+    ) -> Result<u64> {
+        let path = Path::new(root).join(path.as_path());
+
+        // Write under a temporary name first so an existing file is replaced atomically
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".differ-tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        // SFTP only supports modification times with a precision of one second
+        let mtime = modification_date.try_into().with_context(|| {
             format!(
-                "Item path contains invalid UTF-8 characters: {}",
+                "Cannot set a modification date before 1970 on: {}",
                 path.display()
             )
-        })
+        })?;
+
+        self.pools
+            .transfer(|| {
+                let file = self
+                    .sftp
+                    .create(&tmp_path)
+                    .with_context(|| format!("Failed to create file: {}", path.display()))?;
+
+                let mut writer = ThrottledWriter::new(file, self.write_throttle.as_deref());
+
+                let written = io::copy(content, &mut writer)
+                    .with_context(|| format!("Failed to write file: {}", path.display()))?;
+
+                writer
+                    .flush()
+                    .with_context(|| format!("Failed to write file: {}", path.display()))?;
+
+                drop(writer);
+
+                // Keep the permissions of the file being replaced (e.g. its executable bit)
+                let perm = self
+                    .sftp
+                    .lstat(&path)
+                    .ok()
+                    .and_then(|existing| existing.perm)
+                    .map(|perm| perm & 0o7777);
+
+                let stat = FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm,
+                    atime: Some(mtime),
+                    mtime: Some(mtime),
+                };
+
+                self.sftp.setstat(&tmp_path, stat).with_context(|| {
+                    format!("Failed to set modification date on: {}", path.display())
+                })?;
+
+                // Most servers only implement SFTP v3, which doesn't allow renaming over an existing file
+                if self
+                    .sftp
+                    .rename(&tmp_path, &path, Some(RenameFlags::OVERWRITE))
+                    .is_err()
+                {
+                    if self.sftp.stat(&path).is_ok() {
+                        self.sftp.unlink(&path).with_context(|| {
+                            format!("Failed to replace file at: {}", path.display())
+                        })?;
+                    }
+
+                    self.sftp.rename(&tmp_path, &path, None).with_context(|| {
+                        format!("Failed to replace file at: {}", path.display())
+                    })?;
+                }
+
+                Ok(written)
+            })
+            .inspect_err(|_| {
+                let _ = self.sftp.unlink(&tmp_path);
+            })
+    }
+
+    fn create_dir(&self, root: &str, path: &ItemPath) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        self.sftp
+            .mkdir(&path, 0o755)
+            .with_context(|| format!("Failed to create directory at: {}", path.display()))
+    }
+
+    fn remove(&self, root: &str, path: &ItemPath, is_dir: bool) -> Result<()> {
+        let path = Path::new(root).join(path.as_path());
+
+        if is_dir {
+            self.sftp.rmdir(&path)
+        } else {
+            self.sftp.unlink(&path)
+        }
+        .with_context(|| format!("Failed to remove item at: {}", path.display()))
+    }
+}
+
+fn get_relative_path(path: &Path, source: &Path) -> Result<ItemPath> {
+    path.strip_prefix(source)
+        .context("Internal error: failed to strip prefix")
+        .map(ItemPath::from_path)
+}
+
+fn get_filename(path: &Path) -> Result<&OsStr> {
+    path.file_name()
+        .with_context(|| format!("Filename is missing on path: {}", path.display()))
 }
 
 #[derive(Clone)]
 struct ReadDirState {
     sftp: Arc<Sftp>,
-    ignore: Arc<HashSet<String>>,
+    ignore: Arc<HashSet<OsString>>,
+    filters: Arc<WalkFilters>,
+    pools: DriverPools,
     root: Arc<PathBuf>,
     stop_request: Arc<AtomicBool>,
     on_item: Arc<Option<OnItemHandler>>,
     dirs_contents: Arc<Mutex<Vec<DriverItem>>>,
     remaining: Arc<AtomicU32>,
+    /// First error encountered, which stops the walk
+    error: Arc<Mutex<Option<anyhow::Error>>>,
 }
 
-fn stateful_read_dir(dir: PathBuf, state: ReadDirState) -> Result<()> {
+fn stateful_read_dir(dir: PathBuf, state: &ReadDirState) -> Result<()> {
     let mut items = vec![];
 
-    for (item_path, stat) in state.sftp.readdir(&dir)? {
+    let entries = state
+        .sftp
+        .readdir(&dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+    for (item_path, stat) in entries {
         if state.stop_request.load(Ordering::Relaxed) {
             bail!("Process was requested to stop.");
         }
@@ -133,7 +364,7 @@ fn stateful_read_dir(dir: PathBuf, state: ReadDirState) -> Result<()> {
             continue;
         }
 
-        let path = get_relative_utf8_path(&item_path, &state.root)?.to_string();
+        let path = get_relative_path(&item_path, &state.root)?;
 
         if stat.is_dir() {
             metadata = DriverItemMetadata::Directory;
@@ -151,15 +382,44 @@ fn stateful_read_dir(dir: PathBuf, state: ReadDirState) -> Result<()> {
                             item_path.display()
                         )
                     })?,
+                // SFTP only provides modification times with a precision of one second
+                modification_date_nanos: None,
                 size: stat
                     .size
                     .with_context(|| format!("Missing size on item: {}", item_path.display()))?,
+                allocated_size: None,
             })
         } else {
-            bail!("Unknown item type at: {}", item_path.display());
+            // SFTP does not provide device IDs
+            metadata = DriverItemMetadata::Special(match stat.file_type() {
+                FileType::NamedPipe => DriverSpecialMetadata::Fifo,
+                FileType::Socket => DriverSpecialMetadata::Socket,
+                FileType::BlockDevice => DriverSpecialMetadata::BlockDevice { rdev: None },
+                FileType::CharDevice => DriverSpecialMetadata::CharDevice { rdev: None },
+                _ => bail!("Unknown item type at: {}", item_path.display()),
+            });
         }
 
-        let item = DriverItem { path, metadata };
+        if !state.filters.accepts(&metadata) {
+            continue;
+        }
+
+        let depth = path.depth();
+
+        let item = DriverItem {
+            path,
+            metadata,
+            permissions: DriverItemPermissions {
+                mode: stat.perm.map(|perm| perm & 0o7777),
+                uid: stat.uid,
+                gid: stat.gid,
+                // SFTP only provides numeric IDs
+                user: None,
+                group: None,
+            },
+            xattrs: None,
+            hard_link: None,
+        };
 
         if let Some(handler) = state.on_item.as_deref() {
             handler(&item);
@@ -167,29 +427,29 @@ fn stateful_read_dir(dir: PathBuf, state: ReadDirState) -> Result<()> {
 
         items.push(item);
 
-        if metadata.is_dir() {
-            state.remaining.store(
-                state.remaining.load(Ordering::Acquire) + 1,
-                Ordering::Release,
-            );
-
+        if metadata.is_dir() && state.filters.descends_into(depth) {
             stateful_read_dir_spawn(item_path, state.clone());
         }
     }
 
     state.dirs_contents.lock().unwrap().extend(items);
 
-    state.remaining.store(
-        state.remaining.load(Ordering::Acquire) - 1,
-        Ordering::Release,
-    );
-
     Ok(())
 }
 
+/// Read a directory in the scan pool, counting it as remaining until it's done
 fn stateful_read_dir_spawn(dir: PathBuf, state: ReadDirState) {
-    rayon::spawn(move || {
-        // TODO: handle erros
-        stateful_read_dir(dir, state).unwrap()
+    let pools = state.pools.clone();
+
+    state.remaining.fetch_add(1, Ordering::AcqRel);
+
+    pools.spawn_scan(move || {
+        // Errors can't be returned from the pool, so the first one is kept for `find_all`
+        if let Err(err) = stateful_read_dir(dir, &state) {
+            state.stop_request.store(true, Ordering::Relaxed);
+            state.error.lock().unwrap().get_or_insert(err);
+        }
+
+        state.remaining.fetch_sub(1, Ordering::AcqRel);
     });
 }
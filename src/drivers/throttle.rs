@@ -0,0 +1,282 @@
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Error, Result};
+use chrono::{Local, NaiveTime};
+
+use crate::units::parse_size;
+
+/// Bandwidth limit, possibly depending on the time of day
+///
+/// Parsed from a comma-separated list of rates (in bytes per second, e.g. `2M`) or `off`, each
+/// optionally prefixed with a local time window (e.g. `08:00-18:00@1M,10M` limits to 1 MB/s during
+/// work hours and to 10 MB/s otherwise). The first matching window wins, the entry without a
+/// window applies the rest of the time (no limit if there is none).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    windows: Vec<BandwidthWindow>,
+    default: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BandwidthWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    limit: Option<u64>,
+}
+
+impl BandwidthSchedule {
+    /// Get the limit in bytes per second at the provided time of day (`None` if unlimited)
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| {
+                if window.start <= window.end {
+                    window.start <= time && time < window.end
+                } else {
+                    // Window spanning midnight (e.g. `22:00-06:00`)
+                    time >= window.start || time < window.end
+                }
+            })
+            .map_or(self.default, |window| window.limit)
+    }
+}
+
+impl FromStr for BandwidthSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut windows = vec![];
+        let mut default = None;
+        let mut has_default = false;
+
+        for entry in s.split(',').map(str::trim) {
+            let parse_limit = |limit: &str| -> Result<Option<u64>> {
+                match limit.trim() {
+                    "off" => Ok(None),
+                    limit => match parse_size(limit)? {
+                        0 => bail!("Bandwidth limit cannot be zero (use 'off' to disable it)"),
+                        limit => Ok(Some(limit)),
+                    },
+                }
+            };
+
+            match entry.split_once('@') {
+                Some((window, limit)) => {
+                    let (start, end) = window.split_once('-').with_context(|| {
+                        format!(
+                            "Invalid time window '{}' (expected e.g. '08:00-18:00')",
+                            window
+                        )
+                    })?;
+
+                    let parse_time = |time: &str| {
+                        NaiveTime::parse_from_str(time.trim(), "%H:%M").with_context(|| {
+                            format!("Invalid time '{}' (expected e.g. '08:00')", time)
+                        })
+                    };
+
+                    windows.push(BandwidthWindow {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        limit: parse_limit(limit)?,
+                    });
+                }
+
+                None => {
+                    if has_default {
+                        bail!("Only one bandwidth limit can be provided without a time window");
+                    }
+
+                    default = parse_limit(entry)?;
+                    has_default = true;
+                }
+            }
+        }
+
+        Ok(Self { windows, default })
+    }
+}
+
+/// Limits the throughput of all readers and writers sharing it
+pub struct Throttle {
+    schedule: BandwidthSchedule,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    /// Bytes which can be consumed right away (negative when readers and writers must wait)
+    available: f64,
+    last_update: Instant,
+}
+
+impl Throttle {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        Self {
+            schedule,
+            state: Mutex::new(ThrottleState {
+                available: 0.0,
+                last_update: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for bytes which were just read or written, waiting if the limit is exceeded
+    pub fn consume(&self, bytes: usize) {
+        let Some(limit) = self.schedule.limit_at(Local::now().time()) else {
+            return;
+        };
+
+        let limit = limit as f64;
+
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+
+            // Allow bursts of up to one second worth of data
+            let elapsed = state.last_update.elapsed().as_secs_f64();
+            state.available = (state.available + elapsed * limit).min(limit) - bytes as f64;
+            state.last_update = Instant::now();
+
+            if state.available < 0.0 {
+                Duration::from_secs_f64(-state.available / limit)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Reader whose throughput is limited by a [`Throttle`]
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    throttle: Option<&'a Throttle>,
+}
+
+impl<'a, R> ThrottledReader<'a, R> {
+    pub fn new(inner: R, throttle: Option<&'a Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if let Some(throttle) = self.throttle {
+            throttle.consume(read);
+        }
+
+        Ok(read)
+    }
+}
+
+/// Writer whose throughput is limited by a [`Throttle`]
+pub struct ThrottledWriter<'a, W> {
+    inner: W,
+    throttle: Option<&'a Throttle>,
+}
+
+impl<'a, W> ThrottledWriter<'a, W> {
+    pub fn new(inner: W, throttle: Option<&'a Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        if let Some(throttle) = self.throttle {
+            throttle.consume(written);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn limit_at(schedule: &str, at: &str) -> Option<u64> {
+        schedule
+            .parse::<BandwidthSchedule>()
+            .unwrap()
+            .limit_at(time(at))
+    }
+
+    #[test]
+    fn plain_limits_apply_all_day() {
+        assert_eq!(limit_at("2M", "00:00"), Some(2_000_000));
+        assert_eq!(limit_at("512KiB", "23:59"), Some(512 * 1024));
+        assert_eq!(limit_at("off", "12:00"), None);
+    }
+
+    #[test]
+    fn windows_are_matched_in_order() {
+        let schedule = "08:00-18:00@1M, 12:00-14:00@off, 10M";
+
+        assert_eq!(limit_at(schedule, "07:59"), Some(10_000_000));
+        assert_eq!(limit_at(schedule, "08:00"), Some(1_000_000));
+        assert_eq!(limit_at(schedule, "13:00"), Some(1_000_000));
+        assert_eq!(limit_at(schedule, "18:00"), Some(10_000_000));
+
+        // No limit outside of the windows without a default
+        assert_eq!(limit_at("08:00-18:00@1M", "20:00"), None);
+        assert_eq!(limit_at("08:00-18:00@off,1M", "09:00"), None);
+    }
+
+    #[test]
+    fn windows_can_span_midnight() {
+        let schedule = "22:00-06:00@10M,1M";
+
+        assert_eq!(limit_at(schedule, "21:59"), Some(1_000_000));
+        assert_eq!(limit_at(schedule, "22:00"), Some(10_000_000));
+        assert_eq!(limit_at(schedule, "00:00"), Some(10_000_000));
+        assert_eq!(limit_at(schedule, "05:59"), Some(10_000_000));
+        assert_eq!(limit_at(schedule, "06:00"), Some(1_000_000));
+
+        // Windows starting and ending at the same time are empty
+        assert_eq!(limit_at("10:00-10:00@10M,1M", "10:00"), Some(1_000_000));
+    }
+
+    #[test]
+    fn malformed_schedules_are_rejected() {
+        for schedule in [
+            "",
+            "0",
+            "fast",
+            "1M,2M",
+            "08:00@1M",
+            "08:00-@1M",
+            "8h-18h@1M",
+            "25:00-06:00@1M",
+            "08:00-18:00@",
+            "08:00-18:00@0",
+            "08:00-18:00-20:00@1M",
+        ] {
+            assert!(
+                schedule.parse::<BandwidthSchedule>().is_err(),
+                "{:?}",
+                schedule
+            );
+        }
+    }
+}
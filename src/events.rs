@@ -0,0 +1,96 @@
+use std::{fmt, sync::Arc};
+
+use crate::drivers::ItemPath;
+
+/// Side of a comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Source,
+    Destination,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Source => write!(f, "source"),
+            Self::Destination => write!(f, "destination"),
+        }
+    }
+}
+
+/// Phases of the diffing process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffPhase {
+    /// Finding items which only exist in the source
+    Added,
+    /// Finding items which only exist in the destination
+    Deleted,
+    /// Finding items which exist on both sides but differ
+    Modified,
+    /// Finding items which can be created as hard links
+    HardLinks,
+    /// Comparing the checksums of modified files
+    Checksums,
+}
+
+/// Event emitted by the library while it's working
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Started building the snapshot of a directory
+    ScanStarted { side: Side, path: String },
+
+    /// Found a new item while building a snapshot, `items` being the number of items found so far
+    ScanProgress { side: Side, items: usize },
+
+    /// Finished building the snapshot of a directory
+    ScanFinished { side: Side, items: usize },
+
+    /// Started a phase of the diffing process
+    DiffPhase(DiffPhase),
+
+    /// Started copying a file's content
+    TransferStarted { path: ItemPath, size: u64 },
+
+    /// Progress of a file being copied, `transferred` being the number of bytes copied so far
+    TransferProgress {
+        path: ItemPath,
+        transferred: u64,
+        total: u64,
+    },
+
+    /// Finished copying a file's content
+    TransferFinished { path: ItemPath },
+
+    /// Something went wrong but the operation continues
+    Warning(String),
+
+    /// An operation failed (the error is returned as well)
+    Error(String),
+}
+
+pub type EventHandler = Arc<dyn Fn(&Event) + Send + Sync + 'static>;
+
+/// Set of subscribers to which events are dispatched
+///
+/// Handlers may be called from several threads at once
+#[derive(Clone, Default)]
+pub struct Events {
+    handlers: Vec<EventHandler>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler which will receive all events
+    pub fn subscribe(&mut self, handler: impl Fn(&Event) + Send + Sync + 'static) {
+        self.handlers.push(Arc::new(handler));
+    }
+
+    pub fn emit(&self, event: Event) {
+        for handler in &self.handlers {
+            handler(&event);
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! Library to compare two directories, possibly on different drivers (local filesystem, SFTP)
+//!
+//! Snapshots of both sides are built with [`drivers::make_snapshot`], then compared with
+//! [`diffing::build_diff`] and categorized with [`diffing::CategorizedDiff`].
+//!
+//! The library never prints anything, this is left to consumers such as the CLI.
+
+#![forbid(unsafe_code)]
+#![forbid(unused_must_use)]
+
+pub mod diffing;
+pub mod drivers;
+pub mod events;
+pub mod sync;
+pub mod units;
@@ -2,8 +2,6 @@
 #![forbid(unused_must_use)]
 
 mod cli;
-mod diffing;
-mod drivers;
 
 fn main() {
     cli::main();
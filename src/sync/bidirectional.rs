@@ -0,0 +1,998 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+};
+
+use anyhow::{bail, Error, Result};
+use rayon::prelude::{IntoParallelIterator, ParallelExtend, ParallelIterator};
+
+use super::{copy_file, Replica};
+use crate::{
+    diffing::{DiffType, ThreeWayDiff, ThreeWayItem, ThreeWayStatus},
+    drivers::{DriverItem, DriverItemMetadata, ItemPath, Snapshot},
+    events::{Event, Events},
+};
+
+/// Suffix inserted in the name of the older file when keeping both versions of a conflict
+const CONFLICT_SUFFIX: &str = ".conflict";
+
+/// How conflicts between two files are resolved
+///
+/// Except with [`ConflictPolicy::Manual`], a modified file wins over its deletion on the other side.
+/// Other conflicts (directories, type changes, special files) are always left to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Leave conflicts untouched and report them
+    #[default]
+    Manual,
+
+    /// Keep the most recently modified file on both sides
+    NewerWins,
+
+    /// Keep the most recently modified file on both sides, and the other one next to it
+    /// with a `.conflict` suffix (e.g. `notes.conflict.txt`, or `notes.conflict-2.txt` if taken)
+    KeepBoth,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(Self::Manual),
+            "newer" => Ok(Self::NewerWins),
+            "keep-both" => Ok(Self::KeepBoth),
+            _ => bail!(
+                "Unknown conflict policy '{}' (expected 'manual', 'newer' or 'keep-both')",
+                s
+            ),
+        }
+    }
+}
+
+/// One of the two replicas being synchronized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyncSide {
+    A,
+    B,
+}
+
+impl SyncSide {
+    pub fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+/// Operation to perform on one of the replicas
+#[derive(Debug, Clone)]
+pub enum SyncAction {
+    /// Create a directory on `side`
+    CreateDir { side: SyncSide, item: DriverItem },
+
+    /// Copy a file from the other side to `side`
+    CopyFile { side: SyncSide, item: DriverItem },
+
+    /// Remove an item (empty directories only) from `side`
+    Remove {
+        side: SyncSide,
+        path: ItemPath,
+        is_dir: bool,
+    },
+
+    /// Resolve a conflict by copying the newer file over the older one, after copying
+    /// the older one to `conflict_path` on both sides
+    KeepBoth {
+        newer_side: SyncSide,
+        newer: DriverItem,
+        older: DriverItem,
+        conflict_path: ItemPath,
+    },
+}
+
+impl SyncAction {
+    pub fn path(&self) -> &ItemPath {
+        match self {
+            Self::CreateDir { item, .. } | Self::CopyFile { item, .. } => &item.path,
+            Self::Remove { path, .. } => path,
+            Self::KeepBoth { newer, .. } => &newer.path,
+        }
+    }
+
+    /// Number of files and bytes copied by this action
+    pub fn transfer_size(&self) -> (usize, u64) {
+        let size = |item: &DriverItem| match item.metadata {
+            DriverItemMetadata::File(m) => m.size,
+            DriverItemMetadata::Directory | DriverItemMetadata::Special(_) => 0,
+        };
+
+        match self {
+            Self::CopyFile { item, .. } => (1, size(item)),
+            Self::KeepBoth { newer, older, .. } => (3, size(newer) + size(older) * 2),
+            Self::CreateDir { .. } | Self::Remove { .. } => (0, 0),
+        }
+    }
+}
+
+/// Operations needed to bring both replicas in sync
+pub struct SyncPlan {
+    /// Actions, in the order they must be applied
+    pub actions: Vec<SyncAction>,
+
+    /// Conflicts left for the user to resolve
+    pub conflicts: Vec<ThreeWayItem>,
+
+    /// Changes which can't be propagated, with the reason why
+    pub skipped: Vec<(ItemPath, &'static str)>,
+
+    /// Changes which aren't propagated as they are inside a conflicting directory
+    pub blocked: Vec<ItemPath>,
+
+    /// Baseline entries of items which both replicas already agree on (`None` if deleted)
+    in_sync: Vec<(ItemPath, Option<DriverItem>)>,
+}
+
+/// Plan the synchronization of two replicas from their changes relative to the baseline
+pub fn plan_sync(
+    diff: &ThreeWayDiff,
+    a: &Snapshot,
+    b: &Snapshot,
+    policy: ConflictPolicy,
+) -> SyncPlan {
+    let a_items = a
+        .items
+        .iter()
+        .map(|item| (&item.path, item))
+        .collect::<HashMap<_, _>>();
+
+    let b_items = b
+        .items
+        .iter()
+        .map(|item| (&item.path, item))
+        .collect::<HashMap<_, _>>();
+
+    let item = |side: SyncSide, path: &ItemPath| -> DriverItem {
+        let items = match side {
+            SyncSide::A => &a_items,
+            SyncSide::B => &b_items,
+        };
+
+        (*items
+            .get(path)
+            .expect("Internal error: changed item is missing from its snapshot"))
+        .clone()
+    };
+
+    let conflict_dirs = diff
+        .items()
+        .iter()
+        .filter(|item| matches!(item.status, ThreeWayStatus::Conflict { .. }))
+        .map(|item| &item.path)
+        .collect::<HashSet<_>>();
+
+    let mut conflict_paths = HashSet::new();
+
+    let mut plan = SyncPlan {
+        actions: vec![],
+        conflicts: vec![],
+        skipped: vec![],
+        blocked: vec![],
+        in_sync: vec![],
+    };
+
+    for three_way in diff.items() {
+        let path = &three_way.path;
+
+        if ancestors(path).any(|ancestor| conflict_dirs.contains(&ancestor)) {
+            match &three_way.status {
+                ThreeWayStatus::ChangedInBoth { a, .. } => plan.in_sync.push((
+                    path.clone(),
+                    a.new_metadata().map(|_| item(SyncSide::A, path)),
+                )),
+                _ => plan.blocked.push(path.clone()),
+            }
+
+            continue;
+        }
+
+        match &three_way.status {
+            ThreeWayStatus::ChangedInA(change) => {
+                propagate(&mut plan, SyncSide::A, path, change, &item)
+            }
+
+            ThreeWayStatus::ChangedInB(change) => {
+                propagate(&mut plan, SyncSide::B, path, change, &item)
+            }
+
+            ThreeWayStatus::ChangedInBoth { a, .. } => plan.in_sync.push((
+                path.clone(),
+                a.new_metadata().map(|_| item(SyncSide::A, path)),
+            )),
+
+            ThreeWayStatus::Conflict { a, b } => {
+                let new_file = |change: &Option<DiffType>| match change {
+                    Some(change) => match change.new_metadata() {
+                        Some(DriverItemMetadata::File(m)) => Some(Some(m)),
+                        Some(DriverItemMetadata::Directory | DriverItemMetadata::Special(_)) => {
+                            None
+                        }
+                        None => Some(None),
+                    },
+                    // Unchanged directory with changes inside
+                    None => None,
+                };
+
+                let winner = match (new_file(a), new_file(b)) {
+                    _ if policy == ConflictPolicy::Manual => None,
+                    (Some(Some(a)), Some(Some(b))) => {
+                        // Favor the first replica when both were modified at the same time
+                        let newer = if b.modification_date > a.modification_date {
+                            SyncSide::B
+                        } else {
+                            SyncSide::A
+                        };
+
+                        Some((newer, true))
+                    }
+                    (Some(Some(_)), Some(None)) => Some((SyncSide::A, false)),
+                    (Some(None), Some(Some(_))) => Some((SyncSide::B, false)),
+                    _ => None,
+                };
+
+                match winner {
+                    Some((newer_side, true)) if policy == ConflictPolicy::KeepBoth => {
+                        // Never overwrite an existing item, or the copy of a previous conflict
+                        let conflict = (1..)
+                            .map(|n| conflict_path(path, n))
+                            .find(|conflict| {
+                                !a_items.contains_key(conflict)
+                                    && !b_items.contains_key(conflict)
+                                    && !conflict_paths.contains(conflict)
+                            })
+                            .unwrap();
+
+                        conflict_paths.insert(conflict.clone());
+
+                        let mut older = item(newer_side.other(), path);
+                        older.path = conflict;
+
+                        plan.actions.push(SyncAction::KeepBoth {
+                            newer_side,
+                            newer: item(newer_side, path),
+                            conflict_path: older.path.clone(),
+                            older,
+                        });
+                    }
+
+                    Some((newer_side, _)) => plan.actions.push(SyncAction::CopyFile {
+                        side: newer_side.other(),
+                        item: item(newer_side, path),
+                    }),
+
+                    None => plan.conflicts.push(three_way.clone()),
+                }
+            }
+        }
+    }
+
+    // Removals first, deepest items first so directories are empty when they are removed,
+    // then creations, shallowest items first so parent directories exist
+    plan.actions.sort_by_cached_key(|action| {
+        let depth = action.path().depth();
+
+        match action {
+            SyncAction::Remove { .. } => (0, usize::MAX - depth),
+            _ => (1, depth),
+        }
+    });
+
+    plan
+}
+
+/// Plan the actions needed to propagate a change from one side to the other
+fn propagate(
+    plan: &mut SyncPlan,
+    from: SyncSide,
+    path: &ItemPath,
+    change: &DiffType,
+    item: &impl Fn(SyncSide, &ItemPath) -> DriverItem,
+) {
+    let to = from.other();
+
+    let (remove, create) = match change {
+        DiffType::Added(added) => (None, Some(added.new)),
+        DiffType::Modified(modified) => (None, Some(DriverItemMetadata::File(modified.new))),
+        DiffType::TypeChanged(changed) => (Some(changed.prev), Some(changed.new)),
+        DiffType::Deleted(deleted) => (Some(deleted.prev), None),
+        // Metadata isn't synchronized, and hard links aren't detected
+        DiffType::MetadataChanged(_) | DiffType::HardLinked(_) => return,
+    };
+
+    if let Some(DriverItemMetadata::Special(_)) = create {
+        plan.skipped
+            .push((path.clone(), "special files are not synchronized"));
+        return;
+    }
+
+    if let Some(prev) = remove {
+        plan.actions.push(SyncAction::Remove {
+            side: to,
+            path: path.clone(),
+            is_dir: prev.is_dir(),
+        });
+    }
+
+    match create {
+        Some(DriverItemMetadata::Directory) => plan.actions.push(SyncAction::CreateDir {
+            side: to,
+            item: item(from, path),
+        }),
+
+        Some(DriverItemMetadata::File(_)) => plan.actions.push(SyncAction::CopyFile {
+            side: to,
+            item: item(from, path),
+        }),
+
+        Some(DriverItemMetadata::Special(_)) | None => {}
+    }
+}
+
+/// Result of applying a synchronization plan
+pub struct SyncOutcome {
+    /// Snapshot to use as the baseline of the next synchronization
+    pub baseline: Snapshot,
+
+    /// Number of actions which were applied successfully
+    pub applied: usize,
+
+    /// Actions which failed
+    pub errors: Vec<(ItemPath, Error)>,
+}
+
+/// Apply a synchronization plan, actions which fail being reported without stopping the others
+///
+/// Files are copied in parallel, after the directories they go into are created and before
+/// deleted items are removed (except those replaced by an item of another type).
+pub fn apply_sync_plan(
+    plan: &SyncPlan,
+    a: Replica,
+    b: Replica,
+    baseline: &Snapshot,
+    events: &Events,
+) -> SyncOutcome {
+    let replica = |side: SyncSide| match side {
+        SyncSide::A => a,
+        SyncSide::B => b,
+    };
+
+    let mut baseline_items = baseline
+        .items
+        .iter()
+        .map(|item| (item.path.clone(), item.clone()))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut update_baseline = |path: &ItemPath, item: Option<&DriverItem>| match item {
+        Some(item) => {
+            baseline_items.insert(path.clone(), item.clone());
+        }
+        None => {
+            baseline_items.remove(path);
+        }
+    };
+
+    for (path, item) in &plan.in_sync {
+        update_baseline(path, item.as_ref());
+    }
+
+    let apply = |action: &SyncAction| -> Result<()> {
+        let result = match action {
+            SyncAction::CreateDir { side, item } => replica(*side)
+                .driver
+                .create_dir(replica(*side).root, &item.path),
+
+            SyncAction::CopyFile { side, item } => copy_item(
+                replica(side.other()),
+                replica(*side),
+                item,
+                &item.path,
+                events,
+            ),
+
+            SyncAction::Remove { side, path, is_dir } => {
+                replica(*side)
+                    .driver
+                    .remove(replica(*side).root, path, *is_dir)
+            }
+
+            SyncAction::KeepBoth {
+                newer_side,
+                newer,
+                older,
+                conflict_path,
+            } => {
+                let (newer_replica, older_replica) =
+                    (replica(*newer_side), replica(newer_side.other()));
+
+                // The older file is saved on its own side before being overwritten
+                copy_item(older_replica, older_replica, older, &newer.path, events)
+                    .and_then(|()| {
+                        copy_item(older_replica, newer_replica, older, conflict_path, events)
+                    })
+                    .and_then(|()| {
+                        copy_item(newer_replica, older_replica, newer, &newer.path, events)
+                    })
+            }
+        };
+
+        result.inspect_err(|err| events.emit(Event::Error(format!("{:#}", err))))
+    };
+
+    // Paths where items are created, which must be cleared first when their type changed
+    let created = plan
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            SyncAction::CreateDir { side, item } | SyncAction::CopyFile { side, item } => {
+                Some((*side, item.path.clone()))
+            }
+            SyncAction::Remove { .. } | SyncAction::KeepBoth { .. } => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let (clearing, others): (Vec<_>, Vec<_>) =
+        plan.actions.iter().partition(|action| match action {
+            SyncAction::Remove { side, path, .. } => {
+                created.contains(&(*side, path.clone()))
+                    || ancestors(path).any(|parent| created.contains(&(*side, parent)))
+            }
+            _ => false,
+        });
+
+    let (deletions, creations): (Vec<_>, Vec<_>) = others
+        .into_iter()
+        .partition(|action| matches!(action, SyncAction::Remove { .. }));
+
+    let (dirs, copies): (Vec<_>, Vec<_>) = creations
+        .into_iter()
+        .partition(|action| matches!(action, SyncAction::CreateDir { .. }));
+
+    // Actions keep the order of the plan, so directories are empty when removed and exist before
+    // files are copied into them. Copies are independent from each other so they run in parallel,
+    // each driver bounding the number of concurrent ones with its transfer pool, and deletions
+    // only happen once they are done.
+    let mut results = clearing
+        .into_iter()
+        .chain(dirs)
+        .map(|action| (action, apply(action)))
+        .collect::<Vec<_>>();
+
+    results.par_extend(copies.into_par_iter().map(|action| (action, apply(action))));
+
+    results.extend(deletions.into_iter().map(|action| (action, apply(action))));
+
+    let mut applied = 0;
+    let mut errors = vec![];
+
+    for (action, result) in results {
+        match result {
+            Ok(()) => {
+                applied += 1;
+
+                match action {
+                    SyncAction::CreateDir { item, .. } | SyncAction::CopyFile { item, .. } => {
+                        update_baseline(&item.path, Some(item))
+                    }
+                    SyncAction::Remove { path, .. } => update_baseline(path, None),
+                    SyncAction::KeepBoth { newer, older, .. } => {
+                        update_baseline(&newer.path, Some(newer));
+                        update_baseline(&older.path, Some(older));
+                    }
+                }
+            }
+
+            Err(err) => errors.push((action.path().clone(), err)),
+        }
+    }
+
+    SyncOutcome {
+        baseline: Snapshot {
+            path: a.root.to_string(),
+            items: baseline_items.into_values().collect(),
+        },
+        applied,
+        errors,
+    }
+}
+
+/// Copy a file item from one replica to another, reading it from `from_path`
+/// and writing it to the item's path
+fn copy_item(
+    from: Replica,
+    to: Replica,
+    item: &DriverItem,
+    from_path: &ItemPath,
+    events: &Events,
+) -> Result<()> {
+    let DriverItemMetadata::File(metadata) = &item.metadata else {
+        bail!(
+            "Internal error: only files can be copied (item: {})",
+            item.path
+        );
+    };
+
+    copy_file(from, from_path, to, &item.path, metadata, events).map(|_| ())
+}
+
+/// Iterate over the parent directories of a path, deepest first
+fn ancestors(path: &ItemPath) -> impl Iterator<Item = ItemPath> + '_ {
+    let bytes = path.as_bytes();
+
+    (0..bytes.len())
+        .rev()
+        .filter(move |i| bytes[*i] == b'/')
+        .map(move |i| ItemPath::new(bytes[..i].to_vec()))
+}
+
+/// Path of the older file when keeping both versions of a conflict, the suffix being inserted
+/// before the extension (if any), along with `n` if it isn't the first candidate
+fn conflict_path(path: &ItemPath, n: usize) -> ItemPath {
+    let bytes = path.as_bytes();
+    let name_start = bytes.iter().rposition(|b| *b == b'/').map_or(0, |i| i + 1);
+
+    // Leading dots (e.g. `.bashrc`) aren't extensions
+    let split = bytes[name_start..]
+        .iter()
+        .rposition(|b| *b == b'.')
+        .filter(|i| *i > 0)
+        .map_or(bytes.len(), |i| name_start + i);
+
+    let mut conflict = bytes[..split].to_vec();
+    conflict.extend_from_slice(CONFLICT_SUFFIX.as_bytes());
+
+    if n > 1 {
+        conflict.extend_from_slice(format!("-{}", n).as_bytes());
+    }
+
+    conflict.extend_from_slice(&bytes[split..]);
+
+    ItemPath::new(conflict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{self, Read},
+        sync::{atomic::AtomicBool, Arc, Mutex},
+    };
+
+    use crate::{
+        diffing::{build_three_way_diff, DiffOptions, SafetyChecks},
+        drivers::{
+            Driver, DriverFileMetadata, DriverItemPermissions, DriverSpecialMetadata, ItemPath,
+            OnItemHandler, Snapshot, WalkFilters,
+        },
+    };
+
+    /// Driver recording the transfer operations applied to it
+    #[derive(Default)]
+    struct RecordingDriver(Mutex<Vec<String>>);
+
+    impl RecordingDriver {
+        fn record(&self, root: &str, operation: &str, path: &ItemPath) -> Result<()> {
+            let mut log = self.0.lock().unwrap();
+            log.push(format!("{}: {} {}", root, operation, path));
+            Ok(())
+        }
+    }
+
+    impl Driver for RecordingDriver {
+        fn find_all(
+            &self,
+            _dir: &str,
+            _filters: &WalkFilters,
+            _stop_request: Arc<AtomicBool>,
+            _on_item: Option<OnItemHandler>,
+        ) -> Result<Vec<DriverItem>> {
+            unimplemented!()
+        }
+
+        fn checksum(&self, _root: &str, _path: &ItemPath) -> Result<[u8; 32]> {
+            unimplemented!()
+        }
+
+        fn set_permissions(
+            &self,
+            _root: &str,
+            _path: &ItemPath,
+            _permissions: &DriverItemPermissions,
+        ) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn supports_transfers(&self) -> bool {
+            true
+        }
+
+        fn read_file(&self, _root: &str, _path: &ItemPath) -> Result<Box<dyn Read + Send + '_>> {
+            Ok(Box::new(io::empty()))
+        }
+
+        fn write_file(
+            &self,
+            root: &str,
+            path: &ItemPath,
+            _content: &mut (dyn Read + Send),
+            _modification_date: i64,
+            _modification_date_nanos: Option<u32>,
+        ) -> Result<u64> {
+            self.record(root, "write", path).map(|()| 0)
+        }
+
+        fn create_dir(&self, root: &str, path: &ItemPath) -> Result<()> {
+            self.record(root, "mkdir", path)
+        }
+
+        fn remove(&self, root: &str, path: &ItemPath, _is_dir: bool) -> Result<()> {
+            self.record(root, "remove", path)
+        }
+    }
+
+    fn path(path: &str) -> ItemPath {
+        ItemPath::new(path.as_bytes().to_vec())
+    }
+
+    fn item(item_path: &str, metadata: DriverItemMetadata) -> DriverItem {
+        DriverItem {
+            path: path(item_path),
+            metadata,
+            permissions: DriverItemPermissions::default(),
+            xattrs: None,
+            hard_link: None,
+        }
+    }
+
+    fn file(path: &str, modification_date: i64, size: u64) -> DriverItem {
+        item(
+            path,
+            DriverItemMetadata::File(DriverFileMetadata {
+                modification_date,
+                modification_date_nanos: None,
+                size,
+                allocated_size: None,
+            }),
+        )
+    }
+
+    fn dir(path: &str) -> DriverItem {
+        item(path, DriverItemMetadata::Directory)
+    }
+
+    fn snapshot(name: &str, items: Vec<DriverItem>) -> Snapshot {
+        Snapshot {
+            path: name.to_string(),
+            items,
+        }
+    }
+
+    fn plan(
+        baseline: &Snapshot,
+        a: Snapshot,
+        b: Snapshot,
+        policy: ConflictPolicy,
+    ) -> (ThreeWayDiff, SyncPlan) {
+        let diff = build_three_way_diff(baseline, a.clone(), b.clone(), &DiffOptions::default());
+
+        let plan = plan_sync(&diff, &a, &b, policy);
+
+        (diff, plan)
+    }
+
+    fn status<'a>(diff: &'a ThreeWayDiff, item_path: &str) -> &'a ThreeWayStatus {
+        &diff
+            .items()
+            .iter()
+            .find(|item| item.path == path(item_path))
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn conflict_path_keeps_extensions_and_dotfiles() {
+        let conflict = |p: &str, n: usize| conflict_path(&path(p), n).to_string();
+
+        assert_eq!(conflict("notes.txt", 1), "notes.conflict.txt");
+        assert_eq!(conflict("notes.txt", 2), "notes.conflict-2.txt");
+        assert_eq!(
+            conflict("dir/archive.tar.gz", 1),
+            "dir/archive.tar.conflict.gz"
+        );
+        assert_eq!(conflict("README", 1), "README.conflict");
+        assert_eq!(conflict(".bashrc", 1), ".bashrc.conflict");
+        assert_eq!(conflict("home/.bashrc", 3), "home/.bashrc.conflict-3");
+        assert_eq!(conflict("home/.config.bak", 1), "home/.config.conflict.bak");
+        assert_eq!(conflict("v1.2/notes", 1), "v1.2/notes.conflict");
+    }
+
+    #[test]
+    fn changes_on_one_side_are_propagated() {
+        let baseline = snapshot("base", vec![file("kept", 1, 1), file("removed", 1, 1)]);
+
+        let (diff, plan) = plan(
+            &baseline,
+            snapshot(
+                "a",
+                vec![file("kept", 1, 1), file("removed", 1, 1), file("new", 2, 2)],
+            ),
+            snapshot("b", vec![file("kept", 1, 1)]),
+            ConflictPolicy::Manual,
+        );
+
+        assert!(matches!(
+            status(&diff, "new"),
+            ThreeWayStatus::ChangedInA(_)
+        ));
+        assert!(matches!(
+            status(&diff, "removed"),
+            ThreeWayStatus::ChangedInB(_)
+        ));
+
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(plan.actions.len(), 2);
+
+        // Removals come first
+        assert!(matches!(
+            &plan.actions[0],
+            SyncAction::Remove { side: SyncSide::A, path: p, is_dir: false } if *p == path("removed")
+        ));
+        assert!(matches!(
+            &plan.actions[1],
+            SyncAction::CopyFile { side: SyncSide::B, item } if item.path == path("new")
+        ));
+    }
+
+    #[test]
+    fn identical_changes_are_in_sync() {
+        let baseline = snapshot("base", vec![]);
+
+        let (diff, plan) = plan(
+            &baseline,
+            snapshot("a", vec![file("same", 5, 5)]),
+            snapshot("b", vec![file("same", 5, 5)]),
+            ConflictPolicy::Manual,
+        );
+
+        assert!(matches!(
+            status(&diff, "same"),
+            ThreeWayStatus::ChangedInBoth { .. }
+        ));
+        assert!(plan.actions.is_empty());
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(plan.in_sync.len(), 1);
+    }
+
+    #[test]
+    fn modified_files_conflicts_follow_the_policy() {
+        let baseline = snapshot("base", vec![file("notes.txt", 1, 1)]);
+        let a = snapshot("a", vec![file("notes.txt", 3, 3)]);
+        let b = snapshot(
+            "b",
+            vec![file("notes.txt", 2, 2), file("notes.conflict.txt", 1, 1)],
+        );
+
+        let (diff, manual) = plan(&baseline, a.clone(), b.clone(), ConflictPolicy::Manual);
+
+        assert!(matches!(
+            status(&diff, "notes.txt"),
+            ThreeWayStatus::Conflict {
+                a: Some(_),
+                b: Some(_)
+            }
+        ));
+        assert_eq!(manual.conflicts.len(), 1);
+        assert!(manual
+            .actions
+            .iter()
+            .all(|action| action.path() != &path("notes.txt")));
+
+        let (_, newer) = plan(&baseline, a.clone(), b.clone(), ConflictPolicy::NewerWins);
+
+        assert!(newer.conflicts.is_empty());
+        assert!(newer.actions.iter().any(|action| matches!(
+            action,
+            SyncAction::CopyFile { side: SyncSide::B, item } if item.path == path("notes.txt")
+        )));
+
+        let (_, keep_both) = plan(&baseline, a, b, ConflictPolicy::KeepBoth);
+
+        assert!(keep_both.conflicts.is_empty());
+        assert!(keep_both.actions.iter().any(|action| matches!(
+            action,
+            SyncAction::KeepBoth { newer_side: SyncSide::A, conflict_path, .. }
+                if *conflict_path == path("notes.conflict-2.txt")
+        )));
+    }
+
+    #[test]
+    fn modified_file_wins_over_deletion_except_when_manual() {
+        let baseline = snapshot("base", vec![file("doc", 1, 1)]);
+        let a = snapshot("a", vec![]);
+        let b = snapshot("b", vec![file("doc", 2, 2)]);
+
+        let (diff, manual) = plan(&baseline, a.clone(), b.clone(), ConflictPolicy::Manual);
+
+        assert!(matches!(
+            status(&diff, "doc"),
+            ThreeWayStatus::Conflict {
+                a: Some(DiffType::Deleted(_)),
+                b: Some(DiffType::Modified(_))
+            }
+        ));
+        assert_eq!(manual.conflicts.len(), 1);
+        assert!(manual.actions.is_empty());
+
+        for policy in [ConflictPolicy::NewerWins, ConflictPolicy::KeepBoth] {
+            let (_, plan) = plan(&baseline, a.clone(), b.clone(), policy);
+
+            assert!(plan.conflicts.is_empty());
+            assert!(matches!(
+                plan.actions.as_slice(),
+                [SyncAction::CopyFile { side: SyncSide::A, item }] if item.path == path("doc")
+            ));
+        }
+    }
+
+    #[test]
+    fn removed_directory_with_changes_inside_is_a_conflict() {
+        let baseline = snapshot("base", vec![dir("dir"), file("dir/old", 1, 1)]);
+
+        let (diff, plan) = plan(
+            &baseline,
+            snapshot("a", vec![]),
+            snapshot(
+                "b",
+                vec![dir("dir"), file("dir/old", 1, 1), file("dir/new", 1, 1)],
+            ),
+            ConflictPolicy::NewerWins,
+        );
+
+        assert!(matches!(
+            status(&diff, "dir"),
+            ThreeWayStatus::Conflict {
+                a: Some(_),
+                b: None
+            }
+        ));
+        assert_eq!(plan.conflicts.len(), 1);
+        assert!(plan.actions.is_empty());
+        assert_eq!(plan.blocked.len(), 2);
+    }
+
+    #[test]
+    fn special_files_are_skipped() {
+        let baseline = snapshot("base", vec![]);
+
+        let (_, plan) = plan(
+            &baseline,
+            snapshot(
+                "a",
+                vec![item(
+                    "pipe",
+                    DriverItemMetadata::Special(DriverSpecialMetadata::Fifo),
+                )],
+            ),
+            snapshot("b", vec![]),
+            ConflictPolicy::Manual,
+        );
+
+        assert!(plan.actions.is_empty());
+        assert_eq!(plan.skipped.len(), 1);
+    }
+
+    #[test]
+    fn emptied_replica_is_refused() {
+        let mut items = vec![dir("dir"), file("b", 1, 1)];
+        items.extend((0..10).map(|i| file(&format!("dir/{}", i), 1, 1)));
+        let baseline = snapshot("base", items.clone());
+        let a = snapshot("a", items);
+        let b = snapshot("b", vec![]);
+
+        let (_, plan) = plan(&baseline, a.clone(), b.clone(), ConflictPolicy::Manual);
+
+        // Without safety checks, everything would be deleted from the other side
+        let deletions = plan
+            .actions
+            .iter()
+            .filter(|action| {
+                matches!(
+                    action,
+                    SyncAction::Remove {
+                        side: SyncSide::A,
+                        ..
+                    }
+                )
+            })
+            .count();
+
+        assert_eq!(deletions, 12);
+
+        let safety = SafetyChecks::default();
+
+        assert!(safety.check_replicas(&a, &b, &baseline).is_err());
+        assert!(safety
+            .check_sync_deletions("a", deletions, baseline.items.len())
+            .is_err());
+
+        let allowed = SafetyChecks {
+            allow_empty_source: true,
+            ..SafetyChecks::default()
+        };
+
+        assert!(allowed.check_replicas(&a, &b, &baseline).is_ok());
+        assert!(allowed
+            .check_sync_deletions("a", deletions, baseline.items.len())
+            .is_ok());
+    }
+
+    #[test]
+    fn deletions_are_applied_after_copies() {
+        let baseline = snapshot(
+            "base",
+            vec![dir("swap"), file("swap/inner", 1, 1), file("old", 1, 1)],
+        );
+
+        let (_, plan) = plan(
+            &baseline,
+            snapshot(
+                "a",
+                vec![
+                    file("swap", 2, 2),
+                    file("new", 2, 2),
+                    dir("new-dir"),
+                    file("new-dir/file", 2, 2),
+                ],
+            ),
+            baseline.clone(),
+            ConflictPolicy::Manual,
+        );
+
+        let driver = RecordingDriver::default();
+
+        let outcome = apply_sync_plan(
+            &plan,
+            Replica {
+                driver: &driver,
+                root: "a",
+            },
+            Replica {
+                driver: &driver,
+                root: "b",
+            },
+            &baseline,
+            &Events::new(),
+        );
+
+        assert!(outcome.errors.is_empty());
+
+        let mut log = driver.0.into_inner().unwrap();
+
+        // Copies run in parallel so their order isn't deterministic
+        log[3..6].sort();
+
+        assert_eq!(
+            log,
+            [
+                // The directory replaced by a file is removed first
+                "b: remove swap/inner",
+                "b: remove swap",
+                "b: mkdir new-dir",
+                "b: write new",
+                "b: write new-dir/file",
+                "b: write swap",
+                "b: remove old",
+            ]
+        );
+    }
+}
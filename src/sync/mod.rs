@@ -0,0 +1,5 @@
+mod bidirectional;
+mod transfer;
+
+pub use bidirectional::*;
+pub use transfer::*;
@@ -0,0 +1,94 @@
+use std::io::{self, Read};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    drivers::{Driver, DriverFileMetadata, ItemPath},
+    events::{Event, Events},
+};
+
+/// Minimum number of bytes between two progress events for the same file
+const PROGRESS_STEP: u64 = 256 * 1024;
+
+/// Directory on a driver, items being designated by paths relative to it
+#[derive(Clone, Copy)]
+pub struct Replica<'a> {
+    pub driver: &'a (dyn Driver + Sync),
+    pub root: &'a str,
+}
+
+/// Copy a file's content and modification date from one driver to another (or to another path
+/// of the same driver), returning the number of bytes copied
+pub fn copy_file(
+    from: Replica,
+    from_path: &ItemPath,
+    to: Replica,
+    to_path: &ItemPath,
+    metadata: &DriverFileMetadata,
+    events: &Events,
+) -> Result<u64> {
+    events.emit(Event::TransferStarted {
+        path: to_path.clone(),
+        size: metadata.size,
+    });
+
+    let content = from.driver.read_file(from.root, from_path)?;
+
+    let mut content = ProgressReader {
+        inner: content,
+        path: to_path,
+        transferred: 0,
+        reported: 0,
+        total: metadata.size,
+        events,
+    };
+
+    let written = to
+        .driver
+        .write_file(
+            to.root,
+            to_path,
+            &mut content,
+            metadata.modification_date,
+            metadata.modification_date_nanos,
+        )
+        .with_context(|| format!("Failed to copy file: {}", to_path))?;
+
+    events.emit(Event::TransferFinished {
+        path: to_path.clone(),
+    });
+
+    Ok(written)
+}
+
+/// Reader emitting transfer progress events
+struct ProgressReader<'a, R> {
+    inner: R,
+    path: &'a ItemPath,
+    transferred: u64,
+    reported: u64,
+    total: u64,
+    events: &'a Events,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.transferred += read as u64;
+
+        if self.transferred - self.reported >= PROGRESS_STEP
+            || (read == 0 && self.transferred > self.reported)
+        {
+            self.reported = self.transferred;
+
+            self.events.emit(Event::TransferProgress {
+                path: self.path.clone(),
+                transferred: self.transferred,
+                total: self.total,
+            });
+        }
+
+        Ok(read)
+    }
+}
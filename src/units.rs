@@ -0,0 +1,59 @@
+use anyhow::{bail, Context, Result};
+
+/// Split a number from its unit suffix (e.g. "500M" => (500, "M"))
+fn split_unit(s: &str) -> Result<(u64, &str)> {
+    let s = s.trim();
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+
+    let (number, unit) = s.split_at(unit_start);
+
+    let number = number
+        .parse()
+        .with_context(|| format!("Invalid number in '{}'", s))?;
+
+    Ok((number, unit.trim()))
+}
+
+/// Parse a size in bytes, with an optional decimal or binary unit (e.g. "10k", "500M", "4GiB")
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (number, unit) = split_unit(s)?;
+
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => bail!("Unknown size unit '{}' in '{}'", unit, s),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size '{}' is too large", s))
+}
+
+/// Parse an age in seconds (e.g. "10s", "45m", "12h", "30d", "2w")
+pub fn parse_age(s: &str) -> Result<u64> {
+    let (number, unit) = split_unit(s)?;
+
+    let multiplier: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "" => bail!(
+            "Missing unit in age '{}' (expected 's', 'm', 'h', 'd' or 'w')",
+            s
+        ),
+        _ => bail!("Unknown age unit '{}' in '{}'", unit, s),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Age '{}' is too large", s))
+}
@@ -0,0 +1,217 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Temporary directory removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "differ-cli-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+
+    fn write(&self, path: &str, content: &str) -> PathBuf {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_differ-backup"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Run a comparison and get its CSV rows (without the header)
+fn csv_rows(source: &Path, dest: &Path, args: &[&str]) -> Vec<String> {
+    let mut all_args = vec![source.as_os_str(), dest.as_os_str()];
+    all_args.extend(["--format", "csv"].iter().chain(args).map(OsStr::new));
+
+    let output = run(all_args);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn destination_nested_in_source() {
+    let dir = TempDir::new();
+
+    let file = dir.write("src/same", "same");
+    let copy = dir.write("src/backup/same", "same");
+    dir.write("src/backup/stale", "stale");
+
+    fs::File::options()
+        .write(true)
+        .open(&copy)
+        .unwrap()
+        .set_modified(fs::metadata(&file).unwrap().modified().unwrap())
+        .unwrap();
+
+    let rows = csv_rows(&dir.path("src"), &dir.path("src/backup"), &[]);
+
+    assert_eq!(rows.len(), 1, "{:?}", rows);
+    assert!(rows[0].starts_with("deleted,stale,"), "{:?}", rows);
+}
+
+#[test]
+fn markers_are_found_despite_filters() {
+    let dir = TempDir::new();
+
+    let source_marker = dir.write("src/.marker", "");
+    let dest_marker = dir.write("dest/.marker", "");
+    dir.write("src/big", "0123456789");
+
+    // Markers are usually old and empty, so filtered out by these
+    for marker in [&source_marker, &dest_marker] {
+        fs::File::options()
+            .write(true)
+            .open(marker)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+    }
+
+    let rows = csv_rows(
+        &dir.path("src"),
+        &dir.path("dest"),
+        &[
+            "--source-marker",
+            ".marker",
+            "--dest-marker",
+            ".marker",
+            "--newer-than",
+            "30d",
+            "--min-size",
+            "1",
+        ],
+    );
+
+    assert_eq!(rows.len(), 1, "{:?}", rows);
+    assert!(rows[0].starts_with("added,big,"), "{:?}", rows);
+}
+
+#[test]
+fn destination_spelling_is_used_for_destination_operations() {
+    let dir = TempDir::new();
+
+    // Same content, different modification dates so the checksums are compared
+    let source = dir.write("src/Caf\u{e9}", "same");
+    dir.write("dest/Cafe\u{301}", "same");
+
+    fs::File::options()
+        .write(true)
+        .open(&source)
+        .unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+
+    let rows = csv_rows(
+        &dir.path("src"),
+        &dir.path("dest"),
+        &["--normalize", "nfc", "--compare", "checksum"],
+    );
+
+    assert!(rows.is_empty(), "{:?}", rows);
+
+    let photo = dir.write("src2/Photo.JPG", "photo");
+    let dest_photo = dir.write("dest2/photo.jpg", "photo");
+
+    let modified = fs::metadata(&photo).unwrap().modified().unwrap();
+
+    fs::File::options()
+        .write(true)
+        .open(&dest_photo)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    fs::set_permissions(&photo, fs::Permissions::from_mode(0o600)).unwrap();
+    fs::set_permissions(&dest_photo, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let output = run([
+        dir.path("src2").as_os_str(),
+        dir.path("dest2").as_os_str(),
+        OsStr::new("--case-insensitive"),
+        OsStr::new("--permissions"),
+        OsStr::new("--apply-metadata"),
+    ]);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert_eq!(
+        fs::metadata(&dest_photo).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn csv_rows_are_written_by_category() {
+    let dir = TempDir::new();
+
+    dir.write("src/b", "new");
+    dir.write("src/d", "changed");
+    dir.write("src/a", "new");
+    dir.write("dest/d", "old");
+    dir.write("dest/c", "deleted");
+
+    let rows = csv_rows(&dir.path("src"), &dir.path("dest"), &[]);
+
+    let rows = rows
+        .iter()
+        .map(|row| row.split(',').take(2).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>();
+
+    assert_eq!(rows, ["added,a", "added,b", "deleted,c", "modified,d"]);
+
+    // Hard links can only be resolved once the whole diff is built, which must give the same rows
+    let mut linked_rows = csv_rows(&dir.path("src"), &dir.path("dest"), &["--hard-links"])
+        .iter()
+        .map(|row| row.split(',').take(2).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>();
+
+    linked_rows.sort();
+
+    assert_eq!(linked_rows, rows);
+}